@@ -1,9 +1,15 @@
 use std::env;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use localtunnel_client::{broadcast, open_tunnel, ClientConfig};
-use localtunnel_server::{start, AuthMode, ServerConfig};
+use localtunnel_client::{open_tunnel, replay_request, ClientConfig, ReplayEdits, TunnelState};
+use localtunnel_server::{
+    start, AccessLogConfig, AccessLogFormat, AuthMode, ErrorPages, ServerConfig, TlsConfig,
+};
 use tokio::signal;
 
 mod config;
@@ -35,8 +41,48 @@ enum Command {
         /// Max connections allowed to server.
         #[clap(long, default_value = "10")]
         max_conn: u8,
+        /// Credential for servers with authentication, sent in the Authorization header.
         #[clap(long)]
         credential: Option<String>,
+        /// Send the credential and reclaim token in the query string, for servers which don't read the headers.
+        #[clap(long)]
+        credential_in_query: bool,
+        /// Token printed by an earlier run, to take over its subdomain right away.
+        #[clap(long)]
+        reclaim_token: Option<String>,
+        /// Capture the proxied HTTP requests and serve an inspector dashboard at this address, e.g. 127.0.0.1:4040.
+        #[clap(long)]
+        inspect: Option<SocketAddr>,
+        /// Carry all connections over a single multiplexed connection, if the server supports it.
+        #[clap(long)]
+        multiplex: bool,
+        /// Expose a raw TCP service, e.g. a database or SSH, on a dedicated public port.
+        #[clap(long, conflicts_with = "inspect")]
+        tcp: bool,
+    },
+
+    /// Replays a request captured by the inspector of a running client against the local service.
+    Replay {
+        /// Id of the captured request, as shown in the inspector.
+        id: u64,
+        /// Address of the inspector of the running client.
+        #[clap(long, default_value = "127.0.0.1:4040")]
+        inspect: String,
+        /// Replace the request method.
+        #[clap(long)]
+        method: Option<String>,
+        /// Replace the request path and query.
+        #[clap(long)]
+        uri: Option<String>,
+        /// Set a header, e.g. "X-Signature: abc". Can be repeated.
+        #[clap(short = 'H', long = "header")]
+        headers: Vec<String>,
+        /// Remove a captured header. Can be repeated.
+        #[clap(long = "remove-header")]
+        remove_headers: Vec<String>,
+        /// Replace the request body with the content of a file.
+        #[clap(long)]
+        body_file: Option<PathBuf>,
     },
 
     /// Starts proxy server to accept user connections and proxy setup connection.
@@ -58,6 +104,63 @@ enum Command {
         proxy_port: u16,
         #[clap(long)]
         auth_mode: AuthMode,
+        /// Failed registrations allowed per address within --auth-failure-window, any more get a 429. 0 allows any.
+        #[clap(long, default_value = "10")]
+        auth_max_failures: u32,
+        /// Seconds failed registrations are counted for.
+        #[clap(long, default_value = "300")]
+        auth_failure_window: u64,
+        /// Accept tunnel sockets from clients that don't authenticate them, e.g. the original localtunnel client.
+        #[clap(long)]
+        allow_legacy_clients: bool,
+        /// PEM certificate to serve HTTPS on the proxy port, e.g. a wildcard certificate for *.domain.
+        /// Reloaded when the file changes.
+        #[clap(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// PEM private key of the certificate.
+        #[clap(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Show browsers a reminder page before they reach a tunnel, until they enter the tunnel password.
+        #[clap(long)]
+        visitor_reminder: bool,
+        /// Directory of custom error pages named after their status, e.g. 404.html, 502.html and 504.html.
+        #[clap(long)]
+        error_pages: Option<PathBuf>,
+        /// Seconds a tunnel client may take to respond before visitors get a 504.
+        #[clap(long, default_value = "60")]
+        upstream_timeout: u64,
+        /// Seconds a visitor waits for a free tunnel connection before getting a 503, 0 fails at once.
+        #[clap(long, default_value = "10")]
+        socket_wait_timeout: u64,
+        /// Visitors allowed to wait for the connections of one tunnel, any more get a 503 at once.
+        #[clap(long, default_value = "100")]
+        max_waiting_requests: usize,
+        /// Expect a PROXY protocol header (v1 or v2) on every proxy port connection, e.g. behind an AWS NLB or HAProxy.
+        #[clap(long)]
+        proxy_protocol: bool,
+        /// Expect a PROXY protocol header on every api port connection as well.
+        #[clap(long)]
+        api_proxy_protocol: bool,
+        /// Keep the X-Forwarded-* and Forwarded headers of requests, when a reverse proxy in front sets them.
+        #[clap(long)]
+        trust_forwarded_headers: bool,
+        /// Take the address of api callers from X-Forwarded-For, when a reverse proxy in front of the api sets it.
+        #[clap(long)]
+        api_trust_forwarded_headers: bool,
+        /// Log every proxied request to `stdout` or to this file, which is rotated as it grows.
+        #[clap(long)]
+        access_log: Option<String>,
+        #[clap(long, value_enum, default_value = "combined")]
+        access_log_format: AccessLogFormat,
+        /// Rotate the access log file at this size, in megabytes.
+        #[clap(long, default_value = "100")]
+        access_log_max_size: u64,
+        /// Rotated access log files to keep.
+        #[clap(long, default_value = "5")]
+        access_log_max_files: usize,
+        /// JSON file keeping reserved subdomains across restarts, managed through the admin api.
+        #[clap(long)]
+        reservations: Option<PathBuf>,
     },
 }
 
@@ -76,24 +179,95 @@ async fn main() -> Result<()> {
             port,
             max_conn,
             credential,
+            credential_in_query,
+            reclaim_token,
+            inspect,
+            multiplex,
+            tcp,
         } => {
-            let (notify_shutdown, _) = broadcast::channel(1);
             let config = ClientConfig {
                 server: Some(host),
                 subdomain,
                 local_host: Some(local_host),
                 local_port: port,
-                shutdown_signal: notify_shutdown.clone(),
                 max_conn,
                 credential,
+                credential_in_query,
+                reclaim_token,
+                inspect,
+                multiplex,
+                tcp,
+                ..Default::default()
             };
-            let result = open_tunnel(config).await?;
-            log::info!("Tunnel url: {:?}", result);
-            println!("Tunnel url: {}", result);
+            let mut tunnel = open_tunnel(config).await?;
+            log::info!("Tunnel url: {:?}", tunnel.url());
+            println!("Tunnel url: {}", tunnel.url());
+            if let Some(reclaim_token) = tunnel.info().reclaim_token {
+                println!("Reclaim token: {}", reclaim_token);
+            }
+            if let Some(inspect) = inspect {
+                println!("Inspector: http://{}", inspect);
+            }
 
-            signal::ctrl_c().await?;
+            tokio::select! {
+                res = signal::ctrl_c() => res?,
+                state = tunnel.wait() => {
+                    if let TunnelState::Failed(reason) = state {
+                        anyhow::bail!("Tunnel failed: {}", reason);
+                    }
+                }
+            }
+            tunnel.close().await;
             log::info!("Quit");
         }
+        Command::Replay {
+            id,
+            inspect,
+            method,
+            uri,
+            headers,
+            remove_headers,
+            body_file,
+        } => {
+            let headers = headers
+                .iter()
+                .map(|header| match header.split_once(':') {
+                    Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+                    None => Err(anyhow::anyhow!(
+                        "Invalid header, expected \"Name: value\": {}",
+                        header
+                    )),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut edits = ReplayEdits {
+                method,
+                uri,
+                headers,
+                remove_headers,
+                ..Default::default()
+            };
+            if let Some(body_file) = body_file {
+                edits.set_body(std::fs::read(body_file)?);
+            }
+
+            let replayed = replay_request(&inspect, id, &edits).await?;
+            match (replayed.status, replayed.error) {
+                (_, Some(err)) => println!("Replay #{} failed: {}", replayed.id, err),
+                (status, None) => {
+                    println!(
+                        "Replay #{}: {} {} -> {} in {} ms",
+                        replayed.id,
+                        replayed.method,
+                        replayed.uri,
+                        status.unwrap_or_default(),
+                        replayed.duration_ms.unwrap_or_default()
+                    );
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&replayed.response_body.data)?;
+                    stdout.write_all(b"\n")?;
+                }
+            }
+        }
         Command::Server {
             domain,
             port,
@@ -101,7 +275,43 @@ async fn main() -> Result<()> {
             max_sockets,
             proxy_port,
             auth_mode,
+            auth_max_failures,
+            auth_failure_window,
+            allow_legacy_clients,
+            tls_cert,
+            tls_key,
+            visitor_reminder,
+            error_pages,
+            upstream_timeout,
+            socket_wait_timeout,
+            max_waiting_requests,
+            proxy_protocol,
+            api_proxy_protocol,
+            trust_forwarded_headers,
+            api_trust_forwarded_headers,
+            access_log,
+            access_log_format,
+            access_log_max_size,
+            access_log_max_files,
+            reservations,
         } => {
+            let tls = match (tls_cert, tls_key) {
+                (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                    cert_path,
+                    key_path,
+                }),
+                _ => None,
+            };
+            let error_pages = match error_pages {
+                Some(dir) => ErrorPages::from_dir(dir)?,
+                None => ErrorPages::default(),
+            };
+            let access_log = access_log.map(|target| AccessLogConfig {
+                format: access_log_format,
+                path: (target != "stdout").then(|| PathBuf::from(target)),
+                max_size: access_log_max_size * 1024 * 1024,
+                max_files: access_log_max_files,
+            });
             let config = ServerConfig {
                 domain,
                 api_port: port,
@@ -109,6 +319,22 @@ async fn main() -> Result<()> {
                 max_sockets,
                 proxy_port,
                 auth_mode,
+                auth: None,
+                auth_max_failures,
+                auth_failure_window: Duration::from_secs(auth_failure_window),
+                allow_legacy_clients,
+                tls,
+                visitor_reminder,
+                error_pages,
+                upstream_timeout: Duration::from_secs(upstream_timeout),
+                socket_wait_timeout: Duration::from_secs(socket_wait_timeout),
+                max_waiting_requests,
+                proxy_protocol,
+                api_proxy_protocol,
+                trust_forwarded_headers,
+                api_trust_forwarded_headers,
+                access_log,
+                reservations,
             };
             start(config).await?;
         }
@@ -0,0 +1,27 @@
+use crate::TunnelServerInfo;
+
+/// How many events are buffered for slow subscribers before they start lagging.
+pub(crate) const EVENT_CAPACITY: usize = 128;
+
+/// Connection lifecycle events of a tunnel.
+///
+/// Subscribe with [`crate::Tunnel::subscribe`], or pass a sender in [`crate::ClientConfig::events`].
+#[derive(Clone, Debug)]
+pub enum TunnelEvent {
+    /// A socket to the server and its local counterpart are both connected.
+    SocketOpened,
+    /// A socket pair finished, either side closed the connection.
+    SocketClosed,
+    /// The local service refused or failed the connection, it will be retried.
+    LocalConnectFailed { error: String },
+    /// The server refused or failed the connection, it will be retried.
+    RemoteConnectFailed { error: String },
+    /// A connection carried traffic from the server to the local service and back.
+    RequestProxied { bytes_in: u64, bytes_out: u64 },
+    /// The server forgot the tunnel, it is being registered again.
+    Reconnecting,
+    /// The tunnel was registered again, possibly on a new port.
+    Registered(TunnelServerInfo),
+    /// The tunnel was closed or has failed, see [`crate::Tunnel::state`].
+    Closed,
+}
@@ -0,0 +1,996 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::body::{Body, Bytes, Frame, Incoming, SizeHint};
+use hyper::header::{HeaderMap, CONTENT_TYPE, HOST, ORIGIN, UPGRADE};
+use hyper::http::uri::Authority;
+use hyper::service::service_fn;
+use hyper::upgrade::OnUpgrade;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+/// How many requests the inspector keeps, the oldest ones are dropped first.
+const MAX_CAPTURED_REQUESTS: usize = 200;
+/// Bodies are relayed in full, but only captured up to this size.
+const MAX_CAPTURED_BODY: usize = 256 * 1024;
+
+const DASHBOARD_HTML: &str = include_str!("inspect.html");
+
+/// A request relayed through the tunnel, with the response of the local service.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CapturedRequest {
+    pub id: u64,
+    /// Unix time in milliseconds when the request arrived.
+    pub started_at: u64,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub request_headers: Vec<(String, String)>,
+    pub request_body: CapturedBody,
+    pub status: Option<u16>,
+    pub response_headers: Vec<(String, String)>,
+    pub response_body: CapturedBody,
+    /// Time until the response body was complete.
+    pub duration_ms: Option<u64>,
+    pub upgraded: bool,
+    pub error: Option<String>,
+    /// The id of the captured request this one replayed.
+    #[serde(default)]
+    pub replay_of: Option<u64>,
+}
+
+impl CapturedRequest {
+    fn new<B>(id: u64, req: &Request<B>) -> Self {
+        CapturedRequest {
+            id,
+            started_at: unix_millis(),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            version: format!("{:?}", req.version()),
+            request_headers: capture_headers(req.headers()),
+            request_body: CapturedBody::default(),
+            status: None,
+            response_headers: vec![],
+            response_body: CapturedBody::default(),
+            duration_ms: None,
+            upgraded: false,
+            error: None,
+            replay_of: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(into = "SerializedBody", try_from = "SerializedBody")]
+pub struct CapturedBody {
+    pub data: Bytes,
+    /// The full size of the body, which may exceed the captured data.
+    pub size: u64,
+    pub truncated: bool,
+}
+
+/// A [`CapturedBody`] as JSON, the data as text if it is UTF-8 and base64 encoded otherwise.
+#[derive(Serialize, Deserialize)]
+struct SerializedBody {
+    data: String,
+    #[serde(default)]
+    base64: bool,
+    size: u64,
+    truncated: bool,
+}
+
+impl From<CapturedBody> for SerializedBody {
+    fn from(body: CapturedBody) -> Self {
+        let (data, base64) = match std::str::from_utf8(&body.data) {
+            Ok(text) => (text.to_string(), false),
+            Err(_) => (STANDARD.encode(&body.data), true),
+        };
+        SerializedBody {
+            data,
+            base64,
+            size: body.size,
+            truncated: body.truncated,
+        }
+    }
+}
+
+impl TryFrom<SerializedBody> for CapturedBody {
+    type Error = base64::DecodeError;
+
+    fn try_from(body: SerializedBody) -> Result<Self, Self::Error> {
+        let data = if body.base64 {
+            Bytes::from(STANDARD.decode(body.data)?)
+        } else {
+            Bytes::from(body.data)
+        };
+        Ok(CapturedBody {
+            data,
+            size: body.size,
+            truncated: body.truncated,
+        })
+    }
+}
+
+/// Changes applied to a captured request before replaying it, see [`Inspector::replay`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ReplayEdits {
+    pub method: Option<String>,
+    /// Path and query to request instead of the captured one.
+    pub uri: Option<String>,
+    /// Headers to set, replacing captured headers with the same name.
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    #[serde(default)]
+    pub remove_headers: Vec<String>,
+    pub body: Option<String>,
+    /// The `body` is base64 encoded, for binary bodies.
+    #[serde(default)]
+    pub body_base64: bool,
+}
+
+impl ReplayEdits {
+    /// Replace the body, base64 encoded unless it is UTF-8.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        (self.body, self.body_base64) = match String::from_utf8(body) {
+            Ok(text) => (Some(text), false),
+            Err(err) => (Some(STANDARD.encode(err.as_bytes())), true),
+        };
+    }
+}
+
+/// Records the HTTP/1.1 traffic relayed by a tunnel and serves it on a local dashboard.
+///
+/// Enabled with [`crate::ClientConfig::inspect`], available through [`crate::Tunnel::inspector`].
+pub struct Inspector {
+    requests: Mutex<VecDeque<CapturedRequest>>,
+    next_id: AtomicU64,
+    /// The local service, where captured requests are replayed.
+    local_addr: String,
+}
+
+impl Inspector {
+    pub(crate) fn new(local_addr: String) -> Self {
+        Inspector {
+            requests: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            local_addr,
+        }
+    }
+
+    /// The captured requests, newest first.
+    pub fn requests(&self) -> Vec<CapturedRequest> {
+        let requests = self.requests.lock().expect("Inspector lock poisoned");
+        requests.iter().rev().cloned().collect()
+    }
+
+    pub fn request(&self, id: u64) -> Option<CapturedRequest> {
+        let requests = self.requests.lock().expect("Inspector lock poisoned");
+        requests.iter().find(|r| r.id == id).cloned()
+    }
+
+    pub fn clear(&self) {
+        self.requests
+            .lock()
+            .expect("Inspector lock poisoned")
+            .clear();
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn insert(&self, request: CapturedRequest) {
+        let mut requests = self.requests.lock().expect("Inspector lock poisoned");
+        if requests.len() >= MAX_CAPTURED_REQUESTS {
+            requests.pop_front();
+        }
+        requests.push_back(request);
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut CapturedRequest)) {
+        let mut requests = self.requests.lock().expect("Inspector lock poisoned");
+        // The request may have been dropped meanwhile
+        if let Some(request) = requests.iter_mut().rev().find(|r| r.id == id) {
+            f(request);
+        }
+    }
+
+    /// Send a captured request to the local service again, optionally with edits.
+    /// The replay is captured as a new request, which is returned once complete.
+    pub async fn replay(&self, id: u64, edits: ReplayEdits) -> anyhow::Result<CapturedRequest> {
+        let original = self
+            .request(id)
+            .ok_or_else(|| anyhow::anyhow!("No captured request with id {}", id))?;
+        if original.request_body.truncated && edits.body.is_none() {
+            anyhow::bail!(
+                "The body of request {} was too large to capture, it can't be replayed as is",
+                id
+            );
+        }
+
+        let mut builder = Request::builder()
+            .method(edits.method.as_deref().unwrap_or(&original.method))
+            .uri(edits.uri.as_deref().unwrap_or(&original.uri));
+        // The length is recalculated for the replayed body
+        let skipped = |name: &str| {
+            ["content-length", "transfer-encoding"]
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name))
+                || edits
+                    .remove_headers
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(name))
+                || edits
+                    .headers
+                    .iter()
+                    .any(|(h, _)| h.eq_ignore_ascii_case(name))
+        };
+        for (name, value) in &original.request_headers {
+            if !skipped(name) {
+                builder = builder.header(name, value);
+            }
+        }
+        for (name, value) in &edits.headers {
+            builder = builder.header(name, value);
+        }
+        let body = match edits.body {
+            Some(body) if edits.body_base64 => Bytes::from(STANDARD.decode(body)?),
+            Some(body) => Bytes::from(body),
+            None => original.request_body.data.clone(),
+        };
+        let req = builder.body(Full::new(body.clone()))?;
+
+        let started = Instant::now();
+        let mut captured = CapturedRequest::new(self.next_id(), &req);
+        captured.request_body = CapturedBody {
+            size: body.len() as u64,
+            data: body,
+            truncated: false,
+        };
+        captured.replay_of = Some(id);
+
+        let result = async {
+            let local = TcpStream::connect(&self.local_addr).await?;
+            let (mut sender, conn) =
+                hyper::client::conn::http1::handshake(TokioIo::new(local)).await?;
+            tokio::spawn(async move {
+                if let Err(err) = conn.await {
+                    log::debug!("Replay connection failed: {:?}", err);
+                }
+            });
+            let response = sender.send_request(req).await?;
+            let (parts, body) = response.into_parts();
+            let body = body.collect().await?.to_bytes();
+            anyhow::Ok((parts, body))
+        }
+        .await;
+
+        match result {
+            Ok((parts, body)) => {
+                captured.status = Some(parts.status.as_u16());
+                captured.response_headers = capture_headers(&parts.headers);
+                captured.response_body = CapturedBody {
+                    size: body.len() as u64,
+                    truncated: body.len() > MAX_CAPTURED_BODY,
+                    data: body.slice(..std::cmp::min(body.len(), MAX_CAPTURED_BODY)),
+                };
+            }
+            Err(err) => captured.error = Some(err.to_string()),
+        }
+        captured.duration_ms = Some(elapsed_millis(started));
+
+        self.insert(captured.clone());
+        Ok(captured)
+    }
+
+    /// Relay one tunnel connection as HTTP/1.1, capturing every request on it.
+    /// Returns the bytes received from and sent to the remote side.
+    pub(crate) async fn relay(
+        self: &Arc<Self>,
+        remote: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        local: TcpStream,
+    ) -> io::Result<(u64, u64)> {
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(local))
+            .await
+            .map_err(io::Error::other)?;
+        let local_conn = tokio::spawn(async move {
+            if let Err(err) = conn.with_upgrades().await {
+                log::debug!("Local connection failed: {:?}", err);
+            }
+        });
+
+        let traffic = Arc::new(Traffic::default());
+        let remote = CountingIo {
+            inner: remote,
+            traffic: traffic.clone(),
+        };
+        let relay = Arc::new(Relay {
+            inspector: self.clone(),
+            sender: tokio::sync::Mutex::new(sender),
+            upgraded: Mutex::new(None),
+        });
+
+        let service = {
+            let relay = relay.clone();
+            service_fn(move |req| relay.clone().forward(req))
+        };
+        if let Err(err) = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(remote), service)
+            .with_upgrades()
+            .await
+        {
+            log::debug!("Inspected connection failed: {:?}", err);
+        }
+
+        // An upgraded connection keeps the socket busy until either side closes it
+        let upgraded = relay
+            .upgraded
+            .lock()
+            .expect("Inspector lock poisoned")
+            .take();
+        if let Some(task) = upgraded {
+            let _ = task.await;
+        }
+        local_conn.abort();
+
+        Ok((
+            traffic.read.load(Ordering::Relaxed),
+            traffic.written.load(Ordering::Relaxed),
+        ))
+    }
+
+    /// Serve the dashboard until the shutdown signal.
+    pub(crate) async fn serve(
+        self: Arc<Self>,
+        listener: TcpListener,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) {
+        let addr = match listener.local_addr() {
+            Ok(addr) => addr,
+            Err(err) => {
+                log::error!("Inspector has no local address: {:?}", err);
+                return;
+            }
+        };
+        loop {
+            tokio::select! {
+                res = listener.accept() => {
+                    let stream = match res {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            log::warn!("Inspector failed to accept a connection: {:?}", err);
+                            continue;
+                        }
+                    };
+                    let inspector = self.clone();
+                    let service = service_fn(move |req| dashboard(inspector.clone(), addr, req));
+                    tokio::spawn(async move {
+                        if let Err(err) = hyper::server::conn::http1::Builder::new()
+                            .serve_connection(TokioIo::new(stream), service)
+                            .await
+                        {
+                            log::debug!("Failed to serve inspector connection: {:?}", err);
+                        }
+                    });
+                }
+                _ = shutdown_receiver.recv() => return,
+            }
+        }
+    }
+}
+
+/// One inspected tunnel connection, forwarding requests to the local service.
+struct Relay {
+    inspector: Arc<Inspector>,
+    sender: tokio::sync::Mutex<hyper::client::conn::http1::SendRequest<CaptureBody>>,
+    upgraded: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Relay {
+    async fn forward(
+        self: Arc<Self>,
+        mut req: Request<Incoming>,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Infallible> {
+        let started = Instant::now();
+        let id = self.inspector.next_id();
+        self.inspector.insert(CapturedRequest::new(id, &req));
+
+        let request_upgrade = if req.headers().contains_key(UPGRADE) {
+            req.extensions_mut().remove::<OnUpgrade>()
+        } else {
+            None
+        };
+
+        let inspector = self.inspector.clone();
+        let req = req.map(|body| {
+            CaptureBody::new(body, move |captured| {
+                inspector.update(id, |r| r.request_body = captured)
+            })
+        });
+
+        let result = self.sender.lock().await.send_request(req).await;
+        let mut response = match result {
+            Ok(response) => response,
+            Err(err) => {
+                log::warn!("Local service failed to respond: {:?}", err);
+                self.inspector.update(id, |r| {
+                    r.error = Some(err.to_string());
+                    r.duration_ms = Some(elapsed_millis(started));
+                });
+                return Ok(
+                    plain(StatusCode::BAD_GATEWAY, "Local service failed to respond")
+                        .map(|body| body.map_err(|never| match never {}).boxed()),
+                );
+            }
+        };
+
+        let upgraded = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+        self.inspector.update(id, |r| {
+            r.status = Some(response.status().as_u16());
+            r.response_headers = capture_headers(response.headers());
+            r.upgraded = upgraded;
+        });
+
+        if upgraded {
+            let response_upgrade = response.extensions_mut().remove::<OnUpgrade>();
+            if let (Some(request_upgrade), Some(response_upgrade)) =
+                (request_upgrade, response_upgrade)
+            {
+                let task = tokio::spawn(async move {
+                    match tokio::try_join!(request_upgrade, response_upgrade) {
+                        Ok((request_upgraded, response_upgraded)) => {
+                            let mut request_upgraded = TokioIo::new(request_upgraded);
+                            let mut response_upgraded = TokioIo::new(response_upgraded);
+                            if let Err(err) = tokio::io::copy_bidirectional(
+                                &mut request_upgraded,
+                                &mut response_upgraded,
+                            )
+                            .await
+                            {
+                                log::debug!(
+                                    "Coping between upgraded connections failed: {:?}",
+                                    err
+                                );
+                            }
+                        }
+                        Err(err) => log::warn!("Failed to upgrade connection: {:?}", err),
+                    }
+                });
+                *self.upgraded.lock().expect("Inspector lock poisoned") = Some(task);
+            }
+        }
+
+        let inspector = self.inspector.clone();
+        Ok(response.map(|body| {
+            CaptureBody::new(body, move |captured| {
+                inspector.update(id, |r| {
+                    r.response_body = captured;
+                    r.duration_ms = Some(elapsed_millis(started));
+                })
+            })
+            .boxed()
+        }))
+    }
+}
+
+/// Body wrapper that relays every frame and keeps a copy of the data for the inspector.
+struct CaptureBody {
+    inner: Incoming,
+    data: Vec<u8>,
+    size: u64,
+    on_complete: Option<Box<dyn FnOnce(CapturedBody) + Send + Sync>>,
+}
+
+impl CaptureBody {
+    fn new(
+        inner: Incoming,
+        on_complete: impl FnOnce(CapturedBody) + Send + Sync + 'static,
+    ) -> Self {
+        CaptureBody {
+            inner,
+            data: vec![],
+            size: 0,
+            on_complete: Some(Box::new(on_complete)),
+        }
+    }
+
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(CapturedBody {
+                data: Bytes::from(std::mem::take(&mut self.data)),
+                size: self.size,
+                truncated: self.size > MAX_CAPTURED_BODY as u64,
+            });
+        }
+    }
+}
+
+impl Body for CaptureBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    this.size += data.len() as u64;
+                    let room = MAX_CAPTURED_BODY.saturating_sub(this.data.len());
+                    this.data
+                        .extend_from_slice(&data[..std::cmp::min(room, data.len())]);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(None) => {
+                this.complete();
+                Poll::Ready(None)
+            }
+            other => other,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CaptureBody {
+    fn drop(&mut self) {
+        // Also record bodies which were never read to the end
+        self.complete();
+    }
+}
+
+#[derive(Default)]
+struct Traffic {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+/// Counts the bytes going through a stream.
+struct CountingIo<T> {
+    inner: T,
+    traffic: Arc<Traffic>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CountingIo<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        this.traffic.read.fetch_add(read as u64, Ordering::Relaxed);
+        res
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CountingIo<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = res {
+            this.traffic
+                .written
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+async fn dashboard(
+    inspector: Arc<Inspector>,
+    addr: SocketAddr,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    // Pages of other sites may send requests here too, directly or through DNS rebinding
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if !header(HOST).is_some_and(|host| is_dashboard_host(host, addr)) {
+        return Ok(plain(StatusCode::FORBIDDEN, "Unknown host"));
+    }
+    if let Some(origin) = header(ORIGIN) {
+        let own = origin
+            .strip_prefix("http://")
+            .is_some_and(|host| is_dashboard_host(host, addr));
+        if !own {
+            return Ok(plain(
+                StatusCode::FORBIDDEN,
+                "Cross-origin requests are not allowed",
+            ));
+        }
+    }
+
+    let path = req.uri().path().to_string();
+    if req.method() == Method::POST {
+        if let Some(id) = path
+            .strip_prefix("/api/requests/")
+            .and_then(|rest| rest.strip_suffix("/replay"))
+            .and_then(|id| id.parse().ok())
+        {
+            return Ok(replay(inspector, id, req).await);
+        }
+    }
+
+    let response = match (req.method(), path.as_str()) {
+        (&Method::GET, "/") => Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Full::new(Bytes::from_static(DASHBOARD_HTML.as_bytes())))
+            .expect("Invalid response"),
+        (&Method::GET, "/api/requests") => json(&inspector.requests()),
+        (&Method::DELETE, "/api/requests") => {
+            inspector.clear();
+            plain(StatusCode::NO_CONTENT, "")
+        }
+        (&Method::GET, path) => match path
+            .strip_prefix("/api/requests/")
+            .and_then(|id| id.parse().ok())
+            .and_then(|id| inspector.request(id))
+        {
+            Some(request) => json(&request),
+            None => plain(StatusCode::NOT_FOUND, "Not found"),
+        },
+        _ => plain(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+async fn replay(
+    inspector: Arc<Inspector>,
+    id: u64,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    // Unlike plain text or form posts, JSON can't be sent cross-origin without asking first
+    let is_json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"));
+    if !is_json {
+        return plain(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Expected an application/json body",
+        );
+    }
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return plain(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    let edits = if body.is_empty() {
+        ReplayEdits::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(edits) => edits,
+            Err(err) => return plain(StatusCode::BAD_REQUEST, &err.to_string()),
+        }
+    };
+
+    match inspector.replay(id, edits).await {
+        Ok(captured) => json(&captured),
+        Err(err) => plain(StatusCode::UNPROCESSABLE_ENTITY, &err.to_string()),
+    }
+}
+
+/// Ask the inspector dashboard of a running tunnel to replay a captured request.
+pub async fn replay_request(
+    inspector_addr: &str,
+    id: u64,
+    edits: &ReplayEdits,
+) -> anyhow::Result<CapturedRequest> {
+    let uri = format!("http://{}/api/requests/{}/replay", inspector_addr, id);
+    let resp = reqwest::Client::new().post(&uri).json(edits).send().await?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Replay failed ({}): {}", resp.status(), resp.text().await?);
+    }
+    Ok(resp.json().await?)
+}
+
+/// Whether `host`, from a `Host` or `Origin` header, names the dashboard at `addr`
+/// rather than a domain which merely resolves to it.
+fn is_dashboard_host(host: &str, addr: SocketAddr) -> bool {
+    let Ok(authority) = host.parse::<Authority>() else {
+        return false;
+    };
+    if authority.port_u16().unwrap_or(80) != addr.port() {
+        return false;
+    }
+    let name = authority.host();
+    if name.eq_ignore_ascii_case("localhost") {
+        return addr.ip().is_loopback() || addr.ip().is_unspecified();
+    }
+    match name
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => ip == addr.ip() || addr.ip().is_unspecified(),
+        Err(_) => false,
+    }
+}
+
+fn json<T: Serialize>(value: &T) -> Response<Full<Bytes>> {
+    match serde_json::to_vec(value) {
+        Ok(body) => Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .expect("Invalid response"),
+        Err(err) => plain(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+    }
+}
+
+fn plain(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .expect("Invalid response")
+}
+
+fn capture_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            )
+        })
+        .collect()
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn elapsed_millis(started: Instant) -> u64 {
+    started.elapsed().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use http_body_util::{BodyExt, Full};
+    use hyper::body::{Bytes, Incoming};
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{
+        is_dashboard_host, CapturedBody, CapturedRequest, Inspector, ReplayEdits,
+        MAX_CAPTURED_BODY, MAX_CAPTURED_REQUESTS,
+    };
+
+    /// A local service answering every request with its body.
+    async fn echo_service() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    Ok::<_, Infallible>(Response::new(Full::new(body)))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr
+    }
+
+    /// Send `body` through an inspected tunnel connection, returning the response body.
+    async fn relay(inspector: &Arc<Inspector>, local: SocketAddr, body: Vec<u8>) -> Bytes {
+        let (remote, tunnel) = tokio::io::duplex(64 * 1024);
+        let local = TcpStream::connect(local).await.unwrap();
+        let relay = tokio::spawn({
+            let inspector = inspector.clone();
+            async move { inspector.relay(tunnel, local).await.unwrap() }
+        });
+
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(remote))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+        let req = Request::post("/upload")
+            .header("host", "demo.localhost")
+            .body(Full::new(Bytes::from(body.clone())))
+            .unwrap();
+        let response = sender.send_request(req).await.unwrap();
+        let response = response.into_body().collect().await.unwrap().to_bytes();
+        drop(sender);
+
+        let (read, written) = relay.await.unwrap();
+        assert!(read > body.len() as u64);
+        assert!(written > response.len() as u64);
+        response
+    }
+
+    #[tokio::test]
+    async fn relay_captures_bodies() {
+        let local = echo_service().await;
+        let inspector = Arc::new(Inspector::new(local.to_string()));
+
+        let binary = vec![0u8, 159, 146, 150, 255];
+        assert_eq!(relay(&inspector, local, binary.clone()).await, binary);
+        let captured = inspector.requests().remove(0);
+        assert_eq!(captured.method, "POST");
+        assert_eq!(captured.uri, "/upload");
+        assert_eq!(captured.status, Some(200));
+        assert_eq!(captured.request_body.data, binary);
+        assert_eq!(captured.response_body.data, binary);
+        assert!(!captured.request_body.truncated);
+
+        // Relayed in full, captured up to the limit
+        let large = vec![7u8; MAX_CAPTURED_BODY + 10];
+        assert_eq!(
+            relay(&inspector, local, large.clone()).await.len(),
+            large.len()
+        );
+        let captured = inspector.requests().remove(0);
+        for body in [&captured.request_body, &captured.response_body] {
+            assert_eq!(body.data.len(), MAX_CAPTURED_BODY);
+            assert_eq!(body.size, large.len() as u64);
+            assert!(body.truncated);
+        }
+    }
+
+    #[tokio::test]
+    async fn replay_sends_binary_bodies() {
+        let local = echo_service().await;
+        let inspector = Arc::new(Inspector::new(local.to_string()));
+        let binary = vec![0u8, 159, 146, 150, 255];
+        relay(&inspector, local, binary.clone()).await;
+        let id = inspector.requests()[0].id;
+
+        let replayed = inspector.replay(id, ReplayEdits::default()).await.unwrap();
+        assert_eq!(replayed.replay_of, Some(id));
+        assert_eq!(replayed.response_body.data, binary);
+
+        let mut edits = ReplayEdits::default();
+        edits.set_body(vec![1, 2, 255]);
+        assert!(edits.body_base64);
+        let replayed = inspector.replay(id, edits).await.unwrap();
+        assert_eq!(replayed.response_body.data, vec![1, 2, 255]);
+
+        relay(&inspector, local, vec![7u8; MAX_CAPTURED_BODY + 1]).await;
+        let truncated = inspector.requests()[0].id;
+        assert!(inspector
+            .replay(truncated, ReplayEdits::default())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn oldest_requests_are_dropped() {
+        let inspector = Inspector::new(String::new());
+        let req = Request::get("/").body(()).unwrap();
+        for _ in 0..MAX_CAPTURED_REQUESTS + 5 {
+            inspector.insert(CapturedRequest::new(inspector.next_id(), &req));
+        }
+
+        let requests = inspector.requests();
+        assert_eq!(requests.len(), MAX_CAPTURED_REQUESTS);
+        assert_eq!(requests[0].id, MAX_CAPTURED_REQUESTS as u64 + 5);
+        assert_eq!(requests.last().unwrap().id, 6);
+        assert!(inspector.request(5).is_none());
+    }
+
+    #[test]
+    fn bodies_survive_json() {
+        for (data, base64) in [(&b"hello"[..], false), (&[0u8, 159, 146, 150][..], true)] {
+            let body = CapturedBody {
+                data: Bytes::copy_from_slice(data),
+                size: data.len() as u64,
+                truncated: false,
+            };
+            let json = serde_json::to_value(&body).unwrap();
+            assert_eq!(json["base64"], base64);
+            assert_eq!(serde_json::from_value::<CapturedBody>(json).unwrap(), body);
+        }
+    }
+
+    #[tokio::test]
+    async fn dashboard_refuses_foreign_requests() {
+        let local = echo_service().await;
+        let inspector = Arc::new(Inspector::new(local.to_string()));
+        relay(&inspector, local, b"hello".to_vec()).await;
+        let id = inspector.requests()[0].id;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown, _) = tokio::sync::broadcast::channel(1);
+        tokio::spawn(inspector.clone().serve(listener, shutdown.subscribe()));
+
+        let client = reqwest::Client::new();
+        let replay = format!("http://{}/api/requests/{}/replay", addr, id);
+        let status = |req: reqwest::RequestBuilder| async move {
+            req.send().await.unwrap().status().as_u16()
+        };
+
+        // A page posting plain text, as any site may without a preflight
+        let text = client
+            .post(&replay)
+            .header("content-type", "text/plain")
+            .body("{}");
+        assert_eq!(status(text).await, 415);
+        let foreign = client
+            .post(&replay)
+            .header("origin", "http://evil.example")
+            .json(&ReplayEdits::default());
+        assert_eq!(status(foreign).await, 403);
+        // A rebound domain resolving to the dashboard
+        let rebound = client
+            .get(format!("http://{}/api/requests", addr))
+            .header("host", format!("evil.example:{}", addr.port()));
+        assert_eq!(status(rebound).await, 403);
+
+        let own = client
+            .post(&replay)
+            .header("origin", format!("http://localhost:{}", addr.port()))
+            .json(&ReplayEdits::default());
+        assert_eq!(status(own).await, 200);
+        let replayed = super::replay_request(&addr.to_string(), id, &ReplayEdits::default())
+            .await
+            .unwrap();
+        assert_eq!(replayed.response_body.data, "hello");
+        shutdown.send(()).unwrap();
+    }
+
+    #[test]
+    fn dashboard_hosts() {
+        let loopback: SocketAddr = "127.0.0.1:4040".parse().unwrap();
+        assert!(is_dashboard_host("127.0.0.1:4040", loopback));
+        assert!(is_dashboard_host("localhost:4040", loopback));
+        assert!(!is_dashboard_host("localhost:4041", loopback));
+        assert!(!is_dashboard_host("127.0.0.1", loopback));
+        assert!(!is_dashboard_host("evil.example:4040", loopback));
+        assert!(!is_dashboard_host("192.0.2.1:4040", loopback));
+
+        let any: SocketAddr = "[::]:80".parse().unwrap();
+        assert!(is_dashboard_host("[2001:db8::1]", any));
+        assert!(is_dashboard_host("192.0.2.1:80", any));
+        assert!(!is_dashboard_host("evil.example", any));
+    }
+}
@@ -1,12 +1,25 @@
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use socket2::{SockRef, TcpKeepalive};
+use std::future::poll_fn;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::io::{self, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::net::{TcpListener, TcpStream};
 pub use tokio::sync::broadcast;
-use tokio::sync::Semaphore;
-use tokio::time::Duration;
+use tokio::sync::{watch, Notify, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{Duration, Instant};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+pub use crate::event::TunnelEvent;
+pub use crate::inspect::{replay_request, CapturedBody, CapturedRequest, Inspector, ReplayEdits};
+pub use crate::tunnel::{Tunnel, TunnelState};
+
+mod event;
+mod inspect;
+mod tunnel;
 
 pub const PROXY_SERVER: &str = "https://localtunnel.me";
 pub const LOCAL_HOST: &str = "127.0.0.1";
@@ -17,6 +30,21 @@ const TCP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
 #[cfg(not(target_os = "windows"))]
 const TCP_KEEPALIVE_RETRIES: u32 = 5;
 
+/// Number of consecutive remote failures before checking whether the server still knows the tunnel.
+const REMOTE_FAILURE_THRESHOLD: u32 = 3;
+/// A remote socket closed faster than this without carrying any data counts as a failure.
+const IMMEDIATE_CLOSE_TIME: Duration = Duration::from_secs(1);
+/// Sent with the socket token on every tunnel socket before it joins the server pool.
+const SOCKET_AUTH_PREFIX: &str = "LT-AUTH ";
+/// Sent with the socket token on the single connection of a multiplexed tunnel.
+const MUX_AUTH_PREFIX: &str = "LT-MUX ";
+/// Name of the multiplexed transport, servers without support for it don't mention any transport.
+const MUX_TRANSPORT: &str = "yamux";
+/// Carries the reclaim token of a registration, kept out of the query string and so out of access logs.
+const RECLAIM_TOKEN_HEADER: &str = "X-LT-Reclaim-Token";
+/// Tunnel type asking for a raw TCP tunnel on a dedicated public port.
+const TCP_TUNNEL_TYPE: &str = "tcp";
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ProxyResponse {
     id: String,
@@ -28,6 +56,14 @@ struct ProxyResponse {
     cached_url: Option<String>,
     #[serde(default)]
     ip: Option<String>,
+    #[serde(default)]
+    reclaim_token: Option<String>,
+    #[serde(default)]
+    socket_token: Option<String>,
+    #[serde(default)]
+    transport: Option<String>,
+    #[serde(default)]
+    public_port: Option<u16>,
 }
 
 const fn default_max_conn_count() -> u8 {
@@ -37,12 +73,21 @@ const fn default_max_conn_count() -> u8 {
 /// The server detail for client to connect
 #[derive(Clone, Debug)]
 pub struct TunnelServerInfo {
+    pub id: String,
     pub remote_host: String,
     pub remote_port: u16,
     pub remote_ip: Option<String>,
     pub max_conn_count: u8,
     pub url: String,
     pub cached_url: Option<String>,
+    /// Proves ownership of the subdomain, so it can be registered again while still held.
+    pub reclaim_token: Option<String>,
+    /// Authenticates the tunnel sockets, servers without socket authentication don't issue one.
+    pub socket_token: Option<String>,
+    /// Whether the server agreed to carry all connections as streams over one multiplexed connection.
+    pub multiplexed: bool,
+    /// Public port of a TCP tunnel, `url` then reads `tcp://<domain>:<public_port>`.
+    pub public_port: Option<u16>,
 }
 
 pub struct ClientConfig {
@@ -50,13 +95,71 @@ pub struct ClientConfig {
     pub subdomain: Option<String>,
     pub local_host: Option<String>,
     pub local_port: u16,
-    pub shutdown_signal: broadcast::Sender<()>,
+    /// Optional external signal to shut the tunnel down, [`Tunnel::close`] works without it.
+    pub shutdown_signal: Option<broadcast::Sender<()>>,
     pub max_conn: u8,
+    /// Sent in the `Authorization` header as a bearer token.
     pub credential: Option<String>,
+    /// Send the credential and reclaim token in the query string instead, for servers which don't read the headers yet.
+    pub credential_in_query: bool,
+    /// Token from an earlier registration, to take over a subdomain still held by that registration.
+    pub reclaim_token: Option<String>,
+    /// Optional sender to publish [`TunnelEvent`]s on, [`Tunnel::subscribe`] works without it.
+    pub events: Option<broadcast::Sender<TunnelEvent>>,
+    /// Capture the relayed HTTP traffic and serve an inspector dashboard at this address.
+    pub inspect: Option<SocketAddr>,
+    /// Ask for a single multiplexed connection instead of a socket per proxied connection,
+    /// falls back to the sockets if the server doesn't support it.
+    pub multiplex: bool,
+    /// Expose a raw TCP service, e.g. a database, on a dedicated public port instead of HTTP.
+    pub tcp: bool,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            server: None,
+            subdomain: None,
+            local_host: None,
+            local_port: 0,
+            shutdown_signal: None,
+            max_conn: 10,
+            credential: None,
+            credential_in_query: false,
+            reclaim_token: None,
+            events: None,
+            inspect: None,
+            multiplex: false,
+            tcp: false,
+        }
+    }
+}
+
+/// Registration answers meaning retrying won't help, any other failure is retried.
+/// A 429 of a server limiting failed registrations per address, or a 408, passes with time.
+const FATAL_REGISTRATION_STATUSES: [u16; 4] = [400, 401, 403, 409];
+
+/// The server refused to register the tunnel, retrying won't help.
+#[derive(Debug)]
+pub struct RegistrationRejected {
+    pub status: u16,
+    pub message: String,
 }
 
+impl std::fmt::Display for RegistrationRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server rejected the tunnel ({}): {}",
+            self.status, self.message
+        )
+    }
+}
+
+impl std::error::Error for RegistrationRejected {}
+
 /// Open tunnels directly between server and localhost
-pub async fn open_tunnel(config: ClientConfig) -> anyhow::Result<String> {
+pub async fn open_tunnel(config: ClientConfig) -> anyhow::Result<Tunnel> {
     let ClientConfig {
         server,
         subdomain,
@@ -65,62 +168,182 @@ pub async fn open_tunnel(config: ClientConfig) -> anyhow::Result<String> {
         shutdown_signal,
         max_conn,
         credential,
+        credential_in_query,
+        reclaim_token,
+        events,
+        inspect,
+        multiplex,
+        tcp,
     } = config;
-    let tunnel_info = get_tunnel_endpoint(server.as_deref(), subdomain, credential).await?;
-
-    tunnel_to_endpoint(
-        tunnel_info.clone(),
-        local_host,
-        local_port,
-        shutdown_signal,
-        max_conn,
-    )
-    .await;
+    if tcp && inspect.is_some() {
+        anyhow::bail!("The inspector only supports HTTP tunnels");
+    }
+    let registration = Registration {
+        server: server.clone(),
+        credential,
+        credential_in_query,
+        multiplex,
+        tcp,
+    };
+    let tunnel_info = get_tunnel_endpoint(&registration, subdomain, reclaim_token).await?;
+    if tcp && tunnel_info.public_port.is_none() {
+        anyhow::bail!("The server doesn't support TCP tunnels");
+    }
 
     if let Some(cached_url) = &tunnel_info.cached_url {
         log::info!("Cached tunnel url: {}", cached_url);
     }
 
+    let dashboard = match inspect {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            log::info!("Inspector listens at {}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+    let local_host = local_host.unwrap_or(LOCAL_HOST.to_string());
+    let inspector = dashboard
+        .as_ref()
+        .map(|_| Arc::new(Inspector::new(format!("{}:{}", local_host, local_port))));
+
+    let shutdown_signal = shutdown_signal.unwrap_or_else(|| broadcast::channel(1).0);
+    let events = events.unwrap_or_else(|| broadcast::channel(event::EVENT_CAPACITY).0);
+    let supervisor = Arc::new(Supervisor::new(
+        registration,
+        tunnel_info,
+        max_conn,
+        shutdown_signal.clone(),
+        events.clone(),
+    ));
+    let info = supervisor.endpoint.subscribe();
+    let state = supervisor.state.subscribe();
+    let connection_count = supervisor.connection_count.subscribe();
+
+    let task = tunnel_to_endpoint(
+        supervisor,
+        inspector.clone(),
+        dashboard,
+        local_host,
+        local_port,
+        shutdown_signal.clone(),
+    );
+    let tunnel = Tunnel {
+        info,
+        state,
+        connection_count,
+        shutdown_signal,
+        events,
+        inspector,
+        task: Some(task),
+    };
+
     // Try to fetch the tunnel password
     fetch_tunnel_password(server.as_deref()).await;
 
-    Ok(tunnel_info.url)
+    Ok(tunnel)
 }
 
 async fn get_tunnel_endpoint(
-    server: Option<&str>,
+    registration: &Registration,
     subdomain: Option<String>,
-    credential: Option<String>,
+    reclaim_token: Option<String>,
 ) -> anyhow::Result<TunnelServerInfo> {
-    let server = server
+    let server = registration
+        .server
+        .as_deref()
         .unwrap_or(PROXY_SERVER)
         .trim_end_matches('/');
     let assigned_domain = subdomain.as_deref().unwrap_or("?new");
     let mut uri = format!("{}/{}", server, assigned_domain);
-    if let Some(credential) = credential {
+    if registration.multiplex {
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        uri = format!("{}{}transport={}", uri, separator, MUX_TRANSPORT);
+    }
+    if registration.tcp {
         let separator = if uri.contains('?') { '&' } else { '?' };
-        uri = format!("{}{}credential={}", uri, separator, credential);
+        uri = format!("{}{}type={}", uri, separator, TCP_TUNNEL_TYPE);
     }
     log::info!("Request for assign domain: {}", uri);
 
-    let resp = reqwest::get(&uri).await?.json::<ProxyResponse>().await?;
+    // Added after logging the uri, the credential and reclaim token are secret
+    let mut url = reqwest::Url::parse(&uri)?;
+    if registration.credential_in_query {
+        if let Some(credential) = &registration.credential {
+            url.query_pairs_mut().append_pair("credential", credential);
+        }
+        if let Some(reclaim_token) = &reclaim_token {
+            url.query_pairs_mut()
+                .append_pair("reclaim_token", reclaim_token);
+        }
+    }
+    let mut request = reqwest::Client::new().get(url);
+    if !registration.credential_in_query {
+        if let Some(credential) = &registration.credential {
+            request = request.bearer_auth(credential);
+        }
+        if let Some(reclaim_token) = &reclaim_token {
+            request = request.header(RECLAIM_TOKEN_HEADER, reclaim_token);
+        }
+    }
+    let resp = request.send().await?;
+    let status = resp.status();
+    if status.is_client_error() {
+        let message = resp.text().await.unwrap_or_default();
+        if FATAL_REGISTRATION_STATUSES.contains(&status.as_u16()) {
+            return Err(RegistrationRejected {
+                status: status.as_u16(),
+                message,
+            }
+            .into());
+        }
+        anyhow::bail!(
+            "Server answered the registration with {}: {}",
+            status,
+            message
+        );
+    }
+    let resp = resp.json::<ProxyResponse>().await?;
     log::info!("Response from server: {:#?}", resp);
 
     let remote_host = parse_remote_host(server).unwrap_or_else(|| LOCAL_HOST.to_string());
     let remote_ip = resp.ip.clone();
 
     let tunnel_info = TunnelServerInfo {
+        id: resp.id,
         remote_host,
         remote_port: resp.port,
         remote_ip,
         max_conn_count: resp.max_conn_count,
         url: resp.url,
         cached_url: resp.cached_url,
+        reclaim_token: resp.reclaim_token,
+        socket_token: resp.socket_token,
+        multiplexed: resp.transport.as_deref() == Some(MUX_TRANSPORT),
+        public_port: resp.public_port,
     };
 
     Ok(tunnel_info)
 }
 
+/// The lower of the client `max_conn` and the server `max_conn_count`, or 1 when multiplexed.
+fn connection_count(endpoint: &TunnelServerInfo, max_conn: u8) -> u8 {
+    if endpoint.multiplexed {
+        1
+    } else {
+        std::cmp::min(endpoint.max_conn_count, max_conn)
+    }
+}
+
+/// Ask the server whether it still has a tunnel registered under `id`.
+async fn tunnel_is_registered(server: Option<&str>, id: &str) -> anyhow::Result<bool> {
+    let server = server.unwrap_or(PROXY_SERVER).trim_end_matches('/');
+    let status_uri = format!("{}/api/tunnels/{}/status", server, id);
+
+    let resp = reqwest::get(&status_uri).await?;
+    Ok(resp.status() != reqwest::StatusCode::NOT_FOUND)
+}
+
 /// localtunnel-specific password feature
 async fn fetch_tunnel_password(server: Option<&str>) {
     let server = server
@@ -143,69 +366,305 @@ async fn fetch_tunnel_password(server: Option<&str>) {
     }
 }
 
-async fn tunnel_to_endpoint(
-    server: TunnelServerInfo,
-    local_host: Option<String>,
-    local_port: u16,
+/// What is needed to ask the server for the same tunnel again.
+struct Registration {
+    server: Option<String>,
+    credential: Option<String>,
+    credential_in_query: bool,
+    multiplex: bool,
+    tcp: bool,
+}
+
+/// Watches the health of the remote endpoint and registers the tunnel again
+/// once the server has forgotten it, e.g. after a restart or a cleanup.
+struct Supervisor {
+    registration: Registration,
+    endpoint: watch::Sender<TunnelServerInfo>,
+    state: watch::Sender<TunnelState>,
     shutdown_signal: broadcast::Sender<()>,
+    events: broadcast::Sender<TunnelEvent>,
+    failures: AtomicU32,
+    failure_notify: Notify,
+    /// The client side limit, the server may allow fewer connections.
     max_conn: u8,
-) {
-    log::info!("Tunnel server info: {:?}", server);
-    log::info!("Tunnel server info: {:?}", server);
-    let remote_host = Arc::new(server.remote_host);
-    let remote_ip = server.remote_ip.map(Arc::new);
-    let server_port = server.remote_port;
-    let local_host = Arc::new(local_host.unwrap_or(LOCAL_HOST.to_string()));
+    connection_count: watch::Sender<u8>,
+    /// Permits for the pooled sockets, resized when the tunnel is registered again.
+    connection_limit: Arc<Semaphore>,
+}
 
-    let count = std::cmp::min(server.max_conn_count, max_conn);
-    log::info!("Max connection count: {}", count);
-    let limit_connection = Arc::new(Semaphore::new(count.into()));
+impl Supervisor {
+    fn new(
+        registration: Registration,
+        endpoint: TunnelServerInfo,
+        max_conn: u8,
+        shutdown_signal: broadcast::Sender<()>,
+        events: broadcast::Sender<TunnelEvent>,
+    ) -> Self {
+        let count = connection_count(&endpoint, max_conn);
+        Supervisor {
+            registration,
+            endpoint: watch::Sender::new(endpoint),
+            state: watch::Sender::new(TunnelState::Online),
+            shutdown_signal,
+            events,
+            failures: AtomicU32::new(0),
+            failure_notify: Notify::new(),
+            max_conn,
+            connection_count: watch::Sender::new(count),
+            connection_limit: Arc::new(Semaphore::new(count.into())),
+        }
+    }
 
-    let mut shutdown_receiver = shutdown_signal.subscribe();
+    /// The address the socket pool should dial right now.
+    fn remote_addr(&self) -> String {
+        let endpoint = self.endpoint.borrow();
+        let target_host = endpoint
+            .remote_ip
+            .as_deref()
+            .unwrap_or(&endpoint.remote_host);
+        format!("{}:{}", target_host, endpoint.remote_port)
+    }
 
-    tokio::spawn(async move {
+    fn emit(&self, event: TunnelEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(event);
+    }
+
+    /// Preamble proving the socket belongs to the tunnel, if the server asks for one.
+    fn socket_preamble(&self) -> Option<String> {
+        let endpoint = self.endpoint.borrow();
+        endpoint
+            .socket_token
+            .as_ref()
+            .map(|token| format!("{}{}\n", SOCKET_AUTH_PREFIX, token))
+    }
+
+    /// Preamble turning the connection into a multiplexed session.
+    fn mux_preamble(&self) -> String {
+        let endpoint = self.endpoint.borrow();
+        let token = endpoint.socket_token.as_deref().unwrap_or_default();
+        format!("{}{}\n", MUX_AUTH_PREFIX, token)
+    }
+
+    /// Grow or shrink the socket pool to `count` connections.
+    fn resize_pool(&self, count: u8) {
+        let previous = self.connection_count.send_replace(count);
+        if count > previous {
+            self.connection_limit.add_permits((count - previous).into());
+        } else if count < previous {
+            // Sockets in use hand their permits back once they finish
+            let limit = self.connection_limit.clone();
+            tokio::spawn(async move {
+                if let Ok(permits) = limit.acquire_many_owned((previous - count).into()).await {
+                    permits.forget();
+                }
+            });
+        }
+    }
+
+    fn report_failure(&self) {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= REMOTE_FAILURE_THRESHOLD {
+            self.failure_notify.notify_one();
+        }
+    }
+
+    fn report_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+
+    async fn run(&self, mut shutdown_receiver: broadcast::Receiver<()>) {
         loop {
             tokio::select! {
-                res = limit_connection.clone().acquire_owned() => {
-                    let permit = match res {
-                        Ok(permit) => permit,
-                        Err(err) => {
-                            log::error!("Acquire limit connection failed: {:?}", err);
-                            return;
-                        },
+                _ = self.failure_notify.notified() => {}
+                _ = shutdown_receiver.recv() => return,
+            }
+            if self.failures.load(Ordering::Relaxed) < REMOTE_FAILURE_THRESHOLD {
+                continue;
+            }
+
+            tokio::select! {
+                res = self.recover() => {
+                    if let Err(err) = res {
+                        log::error!("Giving up on the tunnel: {}", err);
+                        self.state.send_replace(TunnelState::Failed(err.to_string()));
+                        let _ = self.shutdown_signal.send(());
+                        return;
+                    }
+                }
+                _ = shutdown_receiver.recv() => return,
+            }
+            self.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Registers the tunnel again if the server has forgotten it.
+    /// Fails only when the server rejects the registration.
+    async fn recover(&self) -> Result<(), RegistrationRejected> {
+        let server = self.registration.server.as_deref();
+        let (id, reclaim_token) = {
+            let endpoint = self.endpoint.borrow();
+            (endpoint.id.clone(), endpoint.reclaim_token.clone())
+        };
+
+        match tunnel_is_registered(server, &id).await {
+            Ok(true) => {
+                log::debug!("Tunnel {} is still registered, keep retrying", id);
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(err) => {
+                log::debug!("Failed to check tunnel status: {:?}", err);
+                return Ok(());
+            }
+        }
+
+        log::warn!("Server no longer knows tunnel {}, registering it again", id);
+        self.state.send_replace(TunnelState::Reconnecting);
+        self.emit(TunnelEvent::Reconnecting);
+        let mut retry_count: u64 = 0;
+        loop {
+            match get_tunnel_endpoint(&self.registration, Some(id.clone()), reclaim_token.clone())
+                .await
+            {
+                Ok(info) => {
+                    log::info!("Tunnel registered again at port {}", info.remote_port);
+                    if info.url != self.endpoint.borrow().url {
+                        println!("Tunnel url: {}", info.url);
+                    }
+                    self.resize_pool(connection_count(&info, self.max_conn));
+                    self.endpoint.send_replace(info.clone());
+                    self.state.send_replace(TunnelState::Online);
+                    self.emit(TunnelEvent::Registered(info));
+                    return Ok(());
+                }
+                Err(err) => {
+                    let err = match err.downcast::<RegistrationRejected>() {
+                        Ok(rejected) => return Err(rejected),
+                        Err(err) => err,
                     };
-                    let remote_host = remote_host.clone();
-                    let remote_ip = remote_ip.clone();
-                    let local_host = local_host.clone();
-
-                    let mut shutdown_receiver = shutdown_signal.subscribe();
-
-                    tokio::spawn(async move {
-                        log::info!("Create a new proxy connection.");
-                        tokio::select! {
-                            res = handle_connection(remote_host.as_str(), remote_ip.as_deref().map(|s| s.as_str()), server_port, local_host.as_str(), local_port) => {
-                                match res {
-                                    Ok(_) => log::info!("Connection result: {:?}", res),
-                                    Err(err) => {
-                                        log::error!("Failed to connect to proxy or local server: {:?}", err);
+                    log::warn!("Failed to register tunnel {} again: {:?}", id, err);
+                    retry_count += 1;
+                    tokio::time::sleep(retry_delay(retry_count)).await;
+                }
+            }
+        }
+    }
+}
+
+fn tunnel_to_endpoint(
+    supervisor: Arc<Supervisor>,
+    inspector: Option<Arc<Inspector>>,
+    dashboard: Option<TcpListener>,
+    local_host: String,
+    local_port: u16,
+    shutdown_signal: broadcast::Sender<()>,
+) -> JoinHandle<()> {
+    log::info!("Tunnel server info: {:?}", *supervisor.endpoint.borrow());
+    let local_host = Arc::new(local_host);
+
+    log::info!(
+        "Max connection count: {}",
+        *supervisor.connection_count.borrow()
+    );
+    let limit_connection = supervisor.connection_limit.clone();
+
+    let supervisor_task = {
+        let supervisor = supervisor.clone();
+        let shutdown_receiver = shutdown_signal.subscribe();
+        async move { supervisor.run(shutdown_receiver).await }
+    };
+
+    let dashboard_task = {
+        let inspector = inspector.clone();
+        let shutdown_receiver = shutdown_signal.subscribe();
+        async move {
+            if let (Some(inspector), Some(listener)) = (inspector, dashboard) {
+                inspector.serve(listener, shutdown_receiver).await;
+            }
+        }
+    };
+
+    let mut shutdown_receiver = shutdown_signal.subscribe();
+    let multiplexed = supervisor.endpoint.borrow().multiplexed;
+
+    let connection_task = {
+        let supervisor = supervisor.clone();
+        async move {
+            if multiplexed {
+                serve_multiplexed(
+                    &supervisor,
+                    inspector,
+                    local_host,
+                    local_port,
+                    shutdown_receiver,
+                )
+                .await;
+                return;
+            }
+
+            let mut connections = JoinSet::new();
+            loop {
+                // Subscribe before acquiring, so a shutdown sent meanwhile isn't missed by the new task
+                let mut connection_shutdown = shutdown_signal.subscribe();
+                tokio::select! {
+                    biased;
+                    _ = shutdown_receiver.recv() => {
+                        log::info!("Shutting down the loop immediately");
+                        break;
+                    }
+                    Some(_) = connections.join_next(), if !connections.is_empty() => {}
+                    res = limit_connection.clone().acquire_owned() => {
+                        let permit = match res {
+                            Ok(permit) => permit,
+                            Err(err) => {
+                                log::error!("Acquire limit connection failed: {:?}", err);
+                                break;
+                            },
+                        };
+                        let supervisor = supervisor.clone();
+                        let inspector = inspector.clone();
+                        let local_host = local_host.clone();
+
+                        connections.spawn(async move {
+                            log::info!("Create a new proxy connection.");
+                            tokio::select! {
+                                res = handle_connection(&supervisor, inspector.as_ref(), local_host.as_str(), local_port) => {
+                                    match res {
+                                        Ok(_) => log::info!("Connection result: {:?}", res),
+                                        Err(err) => {
+                                            log::error!("Failed to connect to proxy or local server: {:?}", err);
+                                        }
                                     }
                                 }
+                                _ = connection_shutdown.recv() => {
+                                    log::info!("Shutting down the connection immediately");
+                                }
                             }
-                            _ = shutdown_receiver.recv() => {
-                                log::info!("Shutting down the connection immediately");
-                            }
-                        }
 
-                        drop(permit);
-                    });
-                }
-                _ = shutdown_receiver.recv() => {
-                    log::info!("Shuttign down the loop immediately");
-                    return;
-                }
-            };
+                            drop(permit);
+                        });
+                    }
+                };
+            }
+
+            // Wait for the in-flight connections to finish shutting down
+            while connections.join_next().await.is_some() {}
         }
-    });
+    };
+
+    tokio::spawn(async move {
+        tokio::join!(supervisor_task, dashboard_task, connection_task);
+        supervisor.state.send_if_modified(|state| {
+            if state.is_terminal() {
+                return false;
+            }
+            *state = TunnelState::Closed;
+            true
+        });
+        supervisor.emit(TunnelEvent::Closed);
+        log::info!("Tunnel closed");
+    })
 }
 
 /// copy_bidirectional wrapper for tokio
@@ -236,27 +695,35 @@ where
 
 const MAX_TIMEOUT_MS: u64 = 10000;
 const TIMEOUT_BASE_MS: u64 = 500;
-// Create TcpStream with retry logic
-async fn create_stream<A: tokio::net::ToSocketAddrs>(
-    addr: A,
+
+/// Linear backoff between connection attempts, capped at `MAX_TIMEOUT_MS`.
+fn retry_delay(retry_count: u64) -> Duration {
+    Duration::from_millis(std::cmp::min(retry_count * TIMEOUT_BASE_MS, MAX_TIMEOUT_MS))
+}
+
+// Create TcpStream with retry logic.
+// The address is resolved on every attempt, so it may change while retrying.
+async fn create_stream(
+    addr: impl Fn() -> String,
     alias: &str,
+    on_error: impl Fn(&io::Error),
 ) -> io::Result<TcpStream> {
     let mut retry_count: u64 = 0;
     loop {
-        let stream = match TcpStream::connect(&addr).await {
+        let stream = match TcpStream::connect(addr()).await {
             Ok(stream) => stream,
             Err(err) => {
                 log::debug!("Error connecting to {}: {:?}", alias, err);
+                on_error(&err);
                 if retry_count == 0 {
                     log::info!("Cannot connect to {}, retrying", alias);
                     println!("Cannot connect to {}, retrying...", alias);
                 }
-                retry_count = retry_count + 1;
+                retry_count += 1;
 
-                let sleep_duration_ms =
-                    std::cmp::min(retry_count * TIMEOUT_BASE_MS, MAX_TIMEOUT_MS);
-                log::debug!("Sleeping for {} ms", sleep_duration_ms);
-                tokio::time::sleep(Duration::from_millis(sleep_duration_ms)).await;
+                let sleep_duration = retry_delay(retry_count);
+                log::debug!("Sleeping for {:?}", sleep_duration);
+                tokio::time::sleep(sleep_duration).await;
                 continue;
             }
         };
@@ -270,10 +737,10 @@ async fn create_stream<A: tokio::net::ToSocketAddrs>(
 /// Combine up to two errors into one
 fn combine_err((a, b): (Option<io::Error>, Option<io::Error>)) -> Option<io::Error> {
     match (a, b) {
-        (Some(e1), Some(e2)) => Some(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Multiple errors: '{:?}' and '{:?}'", e1, e2),
-        )),
+        (Some(e1), Some(e2)) => Some(io::Error::other(format!(
+            "Multiple errors: '{:?}' and '{:?}'",
+            e1, e2
+        ))),
         (Some(e1), None) => Some(e1),
         (None, o) => o,
     }
@@ -288,18 +755,34 @@ async fn close_stream_or_handle_error(res: io::Result<TcpStream>) -> Option<io::
 }
 
 async fn handle_connection(
-    remote_host: &str,
-    remote_ip: Option<&str>,
-    remote_port: u16,
+    supervisor: &Supervisor,
+    inspector: Option<&Arc<Inspector>>,
     local_host: &str,
     local_port: u16,
 ) -> Result<(), io::Error> {
-    let target_host = remote_ip.unwrap_or(remote_host);
+    let local_addr = format!("{}:{}", local_host, local_port);
 
     // Open streams to local and remote servers in parallel, If any fails - cleanup
     let (mut local_stream, mut remote_stream) = match futures_util::join!(
-        create_stream(format!("{}:{}", local_host, local_port), "local"),
-        create_stream(format!("{}:{}", target_host, remote_port), "remote")
+        create_stream(
+            || local_addr.clone(),
+            "local",
+            |err| {
+                supervisor.emit(TunnelEvent::LocalConnectFailed {
+                    error: err.to_string(),
+                })
+            }
+        ),
+        create_stream(
+            || supervisor.remote_addr(),
+            "remote",
+            |err| {
+                supervisor.emit(TunnelEvent::RemoteConnectFailed {
+                    error: err.to_string(),
+                });
+                supervisor.report_failure();
+            }
+        )
     ) {
         (Ok(local_stream), Ok(remote_stream)) => (local_stream, remote_stream),
         (a, b) => {
@@ -313,6 +796,11 @@ async fn handle_connection(
             }
         }
     };
+    if let Some(preamble) = supervisor.socket_preamble() {
+        remote_stream.write_all(preamble.as_bytes()).await?;
+    }
+    let connected_at = Instant::now();
+    supervisor.emit(TunnelEvent::SocketOpened);
 
     // configure keepalive on remote socket to early detect network issues and attempt to re-establish the connection.
     let ka = TcpKeepalive::new()
@@ -323,17 +811,34 @@ async fn handle_connection(
     let sf = SockRef::from(&remote_stream);
     sf.set_tcp_keepalive(&ka)?;
 
-    let (rlb, lrb) = match copy_bidirectional(&mut remote_stream, &mut local_stream).await {
-        Ok(value) => value,
-        Err(err) => {
-            log::warn!("copy_bidirectional failed: {}", err);
-            remote_stream.shutdown().await?;
-            local_stream.shutdown().await?;
-            // Suppress error, since It's already handled
-            (0 as u64, 0 as u64)
+    // Inspection has to parse the traffic, otherwise keep the raw fast path
+    let (rlb, lrb) = if let Some(inspector) = inspector {
+        inspector.relay(remote_stream, local_stream).await?
+    } else {
+        match copy_bidirectional(&mut remote_stream, &mut local_stream).await {
+            Ok(value) => value,
+            Err(err) => {
+                log::warn!("copy_bidirectional failed: {}", err);
+                remote_stream.shutdown().await?;
+                local_stream.shutdown().await?;
+                // Suppress error, since It's already handled
+                (0, 0)
+            }
         }
     };
 
+    supervisor.emit(TunnelEvent::SocketClosed);
+    // A server that forgot the tunnel may still accept the socket, but closes it right away.
+    if rlb > 0 {
+        supervisor.emit(TunnelEvent::RequestProxied {
+            bytes_in: rlb,
+            bytes_out: lrb,
+        });
+        supervisor.report_success();
+    } else if connected_at.elapsed() < IMMEDIATE_CLOSE_TIME {
+        supervisor.report_failure();
+    }
+
     log::debug!(
         "Processed connection: remote->local {} bytes, local->remote {} bytes",
         rlb,
@@ -342,6 +847,154 @@ async fn handle_connection(
     Ok(())
 }
 
+/// Keep a multiplexed session with the server open until the shutdown signal,
+/// proxying every stream the server opens on it.
+async fn serve_multiplexed(
+    supervisor: &Arc<Supervisor>,
+    inspector: Option<Arc<Inspector>>,
+    local_host: Arc<String>,
+    local_port: u16,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) {
+    let local_addr = Arc::new(format!("{}:{}", local_host, local_port));
+    let mut retry_count: u64 = 0;
+    loop {
+        let started = Instant::now();
+        tokio::select! {
+            biased;
+            _ = shutdown_receiver.recv() => {
+                log::info!("Shutting down the multiplexed session immediately");
+                return;
+            }
+            res = handle_session(supervisor, inspector.as_ref(), &local_addr) => {
+                if let Err(err) = res {
+                    log::error!("Multiplexed session failed: {:?}", err);
+                }
+            }
+        }
+
+        // Don't hammer a server which keeps closing the session right away
+        if started.elapsed() < IMMEDIATE_CLOSE_TIME {
+            retry_count += 1;
+            tokio::select! {
+                _ = shutdown_receiver.recv() => return,
+                _ = tokio::time::sleep(retry_delay(retry_count)) => {}
+            }
+        } else {
+            retry_count = 0;
+        }
+    }
+}
+
+/// Open a multiplexed session to the server and proxy its streams until it closes.
+async fn handle_session(
+    supervisor: &Arc<Supervisor>,
+    inspector: Option<&Arc<Inspector>>,
+    local_addr: &Arc<String>,
+) -> Result<(), io::Error> {
+    let mut remote_stream = create_stream(
+        || supervisor.remote_addr(),
+        "remote",
+        |err| {
+            supervisor.emit(TunnelEvent::RemoteConnectFailed {
+                error: err.to_string(),
+            });
+            supervisor.report_failure();
+        },
+    )
+    .await?;
+    remote_stream
+        .write_all(supervisor.mux_preamble().as_bytes())
+        .await?;
+    let connected_at = Instant::now();
+    supervisor.emit(TunnelEvent::SocketOpened);
+
+    let ka = TcpKeepalive::new()
+        .with_time(TCP_KEEPALIVE_TIME)
+        .with_interval(TCP_KEEPALIVE_INTERVAL);
+    #[cfg(not(target_os = "windows"))]
+    let ka = ka.with_retries(TCP_KEEPALIVE_RETRIES);
+    let sf = SockRef::from(&remote_stream);
+    sf.set_tcp_keepalive(&ka)?;
+
+    let mut connection = yamux::Connection::new(
+        remote_stream.compat(),
+        yamux::Config::default(),
+        yamux::Mode::Server,
+    );
+    let mut streams = JoinSet::new();
+    let mut proxied = false;
+    let res = loop {
+        tokio::select! {
+            Some(res) = streams.join_next(), if !streams.is_empty() => {
+                proxied |= matches!(res, Ok(Ok(true)));
+            }
+            // Polling the connection also moves the data of the open streams
+            inbound = poll_fn(|cx| connection.poll_next_inbound(cx)) => match inbound {
+                Some(Ok(stream)) => {
+                    log::debug!("New stream {} from the server", stream.id());
+                    let supervisor = supervisor.clone();
+                    let inspector = inspector.cloned();
+                    let local_addr = local_addr.clone();
+                    streams.spawn(async move {
+                        handle_stream(&supervisor, inspector.as_ref(), &local_addr, stream).await
+                    });
+                }
+                Some(Err(err)) => break Err(io::Error::other(err)),
+                None => break Ok(()),
+            },
+        }
+    };
+
+    supervisor.emit(TunnelEvent::SocketClosed);
+    // Same as a pooled socket, a server that forgot the tunnel closes the session right away.
+    if !proxied && connected_at.elapsed() < IMMEDIATE_CLOSE_TIME {
+        supervisor.report_failure();
+    }
+    res
+}
+
+/// Proxy one stream of a multiplexed session to the local service.
+/// Returns whether any data came through it.
+async fn handle_stream(
+    supervisor: &Supervisor,
+    inspector: Option<&Arc<Inspector>>,
+    local_addr: &str,
+    stream: yamux::Stream,
+) -> Result<bool, io::Error> {
+    let mut local_stream = create_stream(
+        || local_addr.to_string(),
+        "local",
+        |err| {
+            supervisor.emit(TunnelEvent::LocalConnectFailed {
+                error: err.to_string(),
+            })
+        },
+    )
+    .await?;
+    let mut remote_stream = stream.compat();
+
+    let (rlb, lrb) = if let Some(inspector) = inspector {
+        inspector.relay(remote_stream, local_stream).await?
+    } else {
+        tokio::io::copy_bidirectional(&mut remote_stream, &mut local_stream).await?
+    };
+
+    if rlb > 0 {
+        supervisor.emit(TunnelEvent::RequestProxied {
+            bytes_in: rlb,
+            bytes_out: lrb,
+        });
+        supervisor.report_success();
+    }
+    log::debug!(
+        "Processed stream: remote->local {} bytes, local->remote {} bytes",
+        rlb,
+        lrb
+    );
+    Ok(rlb > 0)
+}
+
 fn parse_remote_host(server: &str) -> Option<String> {
     if let Ok(parsed) = Url::parse(server) {
         if let Some(host) = parsed.host_str() {
@@ -357,3 +1010,460 @@ fn parse_remote_host(server: &str) -> Option<String> {
         Some(host.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::broadcast;
+    use tokio::time::Duration;
+
+    use super::{
+        get_tunnel_endpoint, open_tunnel, tunnel_is_registered, ClientConfig, Registration,
+        Supervisor, TunnelEvent, TunnelServerInfo, TunnelState, REMOTE_FAILURE_THRESHOLD,
+    };
+
+    /// Serves each request with `respond(path)`, returning the server url and the paths seen.
+    async fn mock_server(
+        respond: impl Fn(&str) -> (u16, String) + Send + Sync + 'static,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let seen = paths.clone();
+        let respond = Arc::new(respond);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let (status, body) = respond(&path);
+                seen.lock().unwrap().push(path);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, paths)
+    }
+
+    fn registration(server: &str) -> Registration {
+        Registration {
+            server: Some(server.to_string()),
+            credential: None,
+            credential_in_query: false,
+            multiplex: false,
+            tcp: false,
+        }
+    }
+
+    fn endpoint(port: u16) -> TunnelServerInfo {
+        TunnelServerInfo {
+            id: "demo".to_string(),
+            remote_host: "127.0.0.1".to_string(),
+            remote_port: port,
+            remote_ip: None,
+            max_conn_count: 2,
+            url: "http://demo.localhost".to_string(),
+            cached_url: None,
+            reclaim_token: Some("secret".to_string()),
+            socket_token: None,
+            multiplexed: false,
+            public_port: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel_is_registered_works() {
+        let (server, _) = mock_server(|path| match path {
+            "/api/tunnels/demo/status" => (200, r#"{"connected_sockets":1}"#.to_string()),
+            _ => (404, String::new()),
+        })
+        .await;
+
+        assert!(tunnel_is_registered(Some(&server), "demo").await.unwrap());
+        assert!(!tunnel_is_registered(Some(&server), "gone").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reclaim_token_in_query_is_escaped() {
+        let (server, paths) = mock_server(|_| {
+            let body =
+                r#"{"id":"demo","port":4000,"max_conn_count":2,"url":"http://demo.localhost"}"#;
+            (200, body.to_string())
+        })
+        .await;
+        let mut registration = registration(&server);
+        registration.multiplex = true;
+        registration.credential_in_query = true;
+
+        let token = Some("a&b=c d".to_string());
+        get_tunnel_endpoint(&registration, Some("demo".to_string()), token)
+            .await
+            .unwrap();
+        assert_eq!(
+            *paths.lock().unwrap(),
+            ["/demo?transport=yamux&reclaim_token=a%26b%3Dc+d"]
+        );
+    }
+
+    #[tokio::test]
+    async fn reclaim_token_is_sent_in_a_header() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let head = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let body =
+                r#"{"id":"demo","port":4000,"max_conn_count":2,"url":"http://demo.localhost"}"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let token = Some("a&b".to_string());
+        get_tunnel_endpoint(&registration(&server), Some("demo".to_string()), token)
+            .await
+            .unwrap();
+        let head = head.await.unwrap();
+        assert!(head.starts_with("GET /demo HTTP/1.1\r\n"));
+        assert!(head.contains("x-lt-reclaim-token: a&b\r\n"));
+    }
+
+    #[tokio::test]
+    async fn supervisor_registers_again() {
+        let (server, paths) = mock_server(|path| {
+            if path.starts_with("/api/tunnels/") {
+                (404, String::new())
+            } else {
+                let body =
+                    r#"{"id":"demo","port":4000,"max_conn_count":2,"url":"http://demo.localhost"}"#;
+                (200, body.to_string())
+            }
+        })
+        .await;
+        let (shutdown, _) = broadcast::channel(1);
+        let (events, mut received) = broadcast::channel(16);
+        let supervisor = Arc::new(Supervisor::new(
+            registration(&server),
+            endpoint(3000),
+            10,
+            shutdown.clone(),
+            events,
+        ));
+        let task = tokio::spawn({
+            let supervisor = supervisor.clone();
+            let shutdown = shutdown.subscribe();
+            async move { supervisor.run(shutdown).await }
+        });
+
+        // Below the threshold the server isn't asked
+        for _ in 1..REMOTE_FAILURE_THRESHOLD {
+            supervisor.report_failure();
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(paths.lock().unwrap().is_empty());
+
+        // A success in between starts counting over
+        supervisor.report_success();
+        supervisor.report_failure();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(paths.lock().unwrap().is_empty());
+
+        for _ in 1..REMOTE_FAILURE_THRESHOLD {
+            supervisor.report_failure();
+        }
+        assert!(matches!(
+            received.recv().await,
+            Ok(TunnelEvent::Reconnecting)
+        ));
+        assert!(
+            matches!(received.recv().await, Ok(TunnelEvent::Registered(info)) if info.remote_port == 4000)
+        );
+        assert_eq!(supervisor.remote_addr(), "127.0.0.1:4000");
+        assert_eq!(*supervisor.state.borrow(), TunnelState::Online);
+        assert_eq!(
+            *paths.lock().unwrap(),
+            ["/api/tunnels/demo/status", "/demo"]
+        );
+
+        shutdown.send(()).unwrap();
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn supervisor_gives_up_when_rejected() {
+        let (server, _) = mock_server(|path| {
+            if path.starts_with("/api/tunnels/") {
+                (404, String::new())
+            } else {
+                (403, "Subdomain is taken".to_string())
+            }
+        })
+        .await;
+        let (shutdown, mut shutdown_received) = broadcast::channel(1);
+        let (events, _) = broadcast::channel(16);
+        let supervisor =
+            Supervisor::new(registration(&server), endpoint(3000), 10, shutdown, events);
+
+        for _ in 0..REMOTE_FAILURE_THRESHOLD {
+            supervisor.report_failure();
+        }
+        supervisor.run(shutdown_received.resubscribe()).await;
+        assert!(
+            matches!(&*supervisor.state.borrow(), TunnelState::Failed(message) if message.contains("403"))
+        );
+        assert!(shutdown_received.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn supervisor_retries_when_limited() {
+        let registrations = AtomicUsize::new(0);
+        let (server, paths) = mock_server(move |path| {
+            if path.starts_with("/api/tunnels/") {
+                return (404, String::new());
+            }
+            match registrations.fetch_add(1, Ordering::SeqCst) {
+                0 => (429, "Too many failed attempts".to_string()),
+                _ => {
+                    let body = r#"{"id":"demo","port":4000,"max_conn_count":2,"url":"http://demo.localhost"}"#;
+                    (200, body.to_string())
+                }
+            }
+        })
+        .await;
+        let (shutdown, _) = broadcast::channel(1);
+        let (events, _) = broadcast::channel(16);
+        let supervisor =
+            Supervisor::new(registration(&server), endpoint(3000), 10, shutdown, events);
+
+        assert!(supervisor.recover().await.is_ok());
+        assert_eq!(*supervisor.state.borrow(), TunnelState::Online);
+        assert_eq!(supervisor.remote_addr(), "127.0.0.1:4000");
+        assert_eq!(
+            *paths.lock().unwrap(),
+            ["/api/tunnels/demo/status", "/demo", "/demo"]
+        );
+    }
+
+    /// A port nobody listens on.
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    /// Answers the first registration with `max_conn_count` 2, later ones with `again`,
+    /// and reports the tunnel as gone.
+    async fn registering_server(remote_port: u16, again: (u16, u8)) -> String {
+        let registrations = AtomicUsize::new(0);
+        let (server, _) = mock_server(move |path| {
+            if path.starts_with("/api/tunnels/") || path == "/mytunnelpassword" {
+                return (404, String::new());
+            }
+            let (status, max_conn_count) = match registrations.fetch_add(1, Ordering::SeqCst) {
+                0 => (200, 2),
+                _ => again,
+            };
+            let body = format!(
+                r#"{{"id":"demo","port":{},"max_conn_count":{},"url":"http://demo.localhost","reclaim_token":"secret"}}"#,
+                remote_port, max_conn_count
+            );
+            (status, body)
+        })
+        .await;
+        server
+    }
+
+    fn config(server: String) -> ClientConfig {
+        ClientConfig {
+            server: Some(server),
+            subdomain: Some("demo".to_string()),
+            local_port: 1,
+            max_conn: 3,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn tunnel_closes() {
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = registering_server(remote.local_addr().unwrap().port(), (200, 2)).await;
+
+        let tunnel = open_tunnel(config(server.clone())).await.unwrap();
+        assert_eq!(tunnel.id(), "demo");
+        assert_eq!(tunnel.connection_count(), 2);
+        assert_eq!(tunnel.state(), TunnelState::Online);
+        let mut events = tunnel.subscribe();
+        tokio::time::timeout(Duration::from_secs(5), tunnel.close())
+            .await
+            .unwrap();
+        assert!(matches!(events.recv().await, Ok(TunnelEvent::Closed)));
+
+        // The external shutdown signal closes it as well
+        let (shutdown, _) = broadcast::channel(1);
+        let mut tunnel = open_tunnel(ClientConfig {
+            shutdown_signal: Some(shutdown.clone()),
+            ..config(server)
+        })
+        .await
+        .unwrap();
+        shutdown.send(()).unwrap();
+        let state = tokio::time::timeout(Duration::from_secs(5), tunnel.wait())
+            .await
+            .unwrap();
+        assert_eq!(state, TunnelState::Closed);
+        assert_eq!(tunnel.state(), TunnelState::Closed);
+    }
+
+    #[tokio::test]
+    async fn tunnel_follows_registration() {
+        let server = registering_server(closed_port().await, (200, 5)).await;
+        let tunnel = open_tunnel(config(server)).await.unwrap();
+        let mut events = tunnel.subscribe();
+        assert_eq!(tunnel.connection_count(), 2);
+
+        // Failing remote sockets get the tunnel registered again
+        let registered = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    TunnelEvent::Reconnecting => {
+                        assert_eq!(tunnel.state(), TunnelState::Reconnecting)
+                    }
+                    TunnelEvent::Registered(info) => break info,
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(registered.max_conn_count, 5);
+        assert_eq!(tunnel.state(), TunnelState::Online);
+        assert_eq!(tunnel.connection_count(), 3);
+        tunnel.close().await;
+    }
+
+    #[tokio::test]
+    async fn tunnel_fails_when_rejected() {
+        let server = registering_server(closed_port().await, (403, 2)).await;
+        let mut tunnel = open_tunnel(config(server)).await.unwrap();
+
+        let state = tokio::time::timeout(Duration::from_secs(10), tunnel.wait())
+            .await
+            .unwrap();
+        assert!(matches!(state, TunnelState::Failed(message) if message.contains("403")));
+        assert!(tunnel.state().is_terminal());
+    }
+
+    #[tokio::test]
+    async fn events_follow_the_lifecycle() {
+        // The first registration points at a server which forgot the tunnel and drops its sockets
+        let forgetful = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let forgetful_port = forgetful.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = forgetful.accept().await {
+                drop(socket);
+            }
+        });
+        let remote = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote_port = remote.local_addr().unwrap().port();
+        // The local service hangs up once the visitor does
+        let local = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_port = local.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = local.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 64];
+                    while matches!(socket.read(&mut buf).await, Ok(n) if n > 0) {}
+                });
+            }
+        });
+
+        let registrations = AtomicUsize::new(0);
+        let (server, _) = mock_server(move |path| {
+            if path.starts_with("/api/tunnels/") || path == "/mytunnelpassword" {
+                return (404, String::new());
+            }
+            let port = match registrations.fetch_add(1, Ordering::SeqCst) {
+                0 => forgetful_port,
+                _ => remote_port,
+            };
+            let body = format!(
+                r#"{{"id":"demo","port":{},"max_conn_count":1,"url":"http://demo.localhost"}}"#,
+                port
+            );
+            (200, body)
+        })
+        .await;
+        let (events, mut received) = broadcast::channel(128);
+        let tunnel = open_tunnel(ClientConfig {
+            local_port,
+            events: Some(events),
+            ..config(server)
+        })
+        .await
+        .unwrap();
+
+        let mut seen = Vec::new();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let event = received.recv().await.unwrap();
+                let opened = matches!(event, TunnelEvent::SocketOpened);
+                seen.push(event);
+                // Connected again after registering again
+                if opened && seen.iter().any(|e| matches!(e, TunnelEvent::Registered(_))) {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        let position = |wanted: fn(&TunnelEvent) -> bool| seen.iter().position(wanted).unwrap();
+        let opened = position(|e| matches!(e, TunnelEvent::SocketOpened));
+        let closed = position(|e| matches!(e, TunnelEvent::SocketClosed));
+        let reconnecting = position(|e| matches!(e, TunnelEvent::Reconnecting));
+        let registered = position(|e| matches!(e, TunnelEvent::Registered(_)));
+        assert!(opened < closed && closed < reconnecting && reconnecting < registered);
+        assert!(
+            matches!(&seen[registered], TunnelEvent::Registered(info) if info.remote_port == remote_port)
+        );
+        assert!(!seen
+            .iter()
+            .any(|e| matches!(e, TunnelEvent::RequestProxied { .. })));
+
+        tunnel.close().await;
+        let closed = loop {
+            match received.recv().await {
+                Ok(TunnelEvent::Closed) => break true,
+                Ok(_) => continue,
+                Err(_) => break false,
+            }
+        };
+        assert!(closed);
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+
+use crate::{Inspector, TunnelEvent, TunnelServerInfo};
+
+/// Lifecycle of an open tunnel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TunnelState {
+    /// Registered with the server and serving requests.
+    Online,
+    /// The server forgot the tunnel and it is being registered again.
+    Reconnecting,
+    /// Closed via [`Tunnel::close`] or the shutdown signal.
+    Closed,
+    /// The server refused to register the tunnel again.
+    Failed(String),
+}
+
+impl TunnelState {
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, TunnelState::Closed | TunnelState::Failed(_))
+    }
+}
+
+/// Handle to an open tunnel, returned by [`crate::open_tunnel`].
+///
+/// Dropping the handle shuts the tunnel down in the background,
+/// use [`Tunnel::close`] to also wait for all proxy connections to finish.
+pub struct Tunnel {
+    pub(crate) info: watch::Receiver<TunnelServerInfo>,
+    pub(crate) state: watch::Receiver<TunnelState>,
+    pub(crate) connection_count: watch::Receiver<u8>,
+    pub(crate) shutdown_signal: broadcast::Sender<()>,
+    pub(crate) events: broadcast::Sender<TunnelEvent>,
+    pub(crate) inspector: Option<Arc<Inspector>>,
+    pub(crate) task: Option<JoinHandle<()>>,
+}
+
+impl Tunnel {
+    /// The server detail currently in use, it changes when the tunnel is registered again.
+    pub fn info(&self) -> TunnelServerInfo {
+        self.info.borrow().clone()
+    }
+
+    pub fn id(&self) -> String {
+        self.info.borrow().id.clone()
+    }
+
+    pub fn url(&self) -> String {
+        self.info.borrow().url.clone()
+    }
+
+    /// The number of connections kept open to the server,
+    /// the lower of the client `max_conn` and the server `max_conn_count`, or 1 when multiplexed.
+    /// It follows the server limit when the tunnel is registered again.
+    pub fn connection_count(&self) -> u8 {
+        *self.connection_count.borrow()
+    }
+
+    pub fn state(&self) -> TunnelState {
+        self.state.borrow().clone()
+    }
+
+    /// Subscribe to the connection lifecycle events from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<TunnelEvent> {
+        self.events.subscribe()
+    }
+
+    /// The captured requests, if the tunnel was opened with `inspect`.
+    pub fn inspector(&self) -> Option<Arc<Inspector>> {
+        self.inspector.clone()
+    }
+
+    /// Resolves once the tunnel is closed or has failed, and returns that final state.
+    pub async fn wait(&mut self) -> TunnelState {
+        match self.state.wait_for(TunnelState::is_terminal).await {
+            Ok(state) => state.clone(),
+            // The background task is gone without reporting, treat it as closed
+            Err(_) => TunnelState::Closed,
+        }
+    }
+
+    /// Shut the tunnel down and wait for all proxy connections to finish.
+    pub async fn close(mut self) {
+        let _ = self.shutdown_signal.send(());
+        if let Some(task) = self.task.take() {
+            if let Err(err) = task.await {
+                log::error!("Tunnel task failed: {:?}", err);
+            }
+        }
+    }
+}
+
+impl Drop for Tunnel {
+    fn drop(&mut self) {
+        if self.task.is_some() {
+            let _ = self.shutdown_signal.send(());
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! One line per proxied request, in JSON or the Combined Log Format, to stdout or a rotating file.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::mpsc::{sync_channel, SyncSender, TrySendError},
+    task::{Context, Poll},
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use serde::Serialize;
+
+use crate::proxy::ProxyBody;
+
+/// Lines waiting to be written, any more are dropped rather than slowing down the proxy.
+const QUEUE_LEN: usize = 4096;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum AccessLogFormat {
+    /// Apache Combined Log Format, followed by the subdomain, latency, upgrade flag and tunnel connection.
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub format: AccessLogFormat,
+    /// Write to this file instead of stdout.
+    pub path: Option<PathBuf>,
+    /// Rotate the file once it grows past this many bytes.
+    pub max_size: u64,
+    /// Rotated files kept beside the current one, as `<path>.1` to `<path>.<max_files>`.
+    pub max_files: usize,
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig {
+            format: AccessLogFormat::default(),
+            path: None,
+            max_size: 100 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// A proxied request, logged once its response is complete.
+#[derive(Debug, Clone, Serialize)]
+pub struct Entry {
+    #[serde(skip)]
+    pub time: SystemTime,
+    pub subdomain: String,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    /// Bytes of the response body.
+    pub bytes: u64,
+    pub latency_ms: u64,
+    pub upgrade: bool,
+    /// The tunnel connection which carried the request, a pooled socket or a multiplexed stream.
+    pub connection: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Entry {
+    fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Json => {
+                #[derive(Serialize)]
+                struct Line<'a> {
+                    time: String,
+                    #[serde(flatten)]
+                    entry: &'a Entry,
+                }
+                let line = Line {
+                    time: rfc3339(self.time),
+                    entry: self,
+                };
+                serde_json::to_string(&line).expect("Serializable access log entry")
+            }
+            AccessLogFormat::Combined => format!(
+                "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {} {}ms upgrade={} {}",
+                self.client_ip,
+                clf_time(self.time),
+                escape(&self.method),
+                escape(&self.path),
+                escape(&self.protocol),
+                self.status,
+                self.bytes,
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+                escape(&self.subdomain),
+                self.latency_ms,
+                self.upgrade,
+                escape(self.connection.as_deref().unwrap_or("-")),
+            ),
+        }
+    }
+}
+
+/// Escape a field of a combined log line like Apache does,
+/// so visitors can't end a quoted field or a line early.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Handle to the writer thread of the access log.
+#[derive(Clone)]
+pub struct AccessLog {
+    lines: SyncSender<String>,
+    format: AccessLogFormat,
+}
+
+impl AccessLog {
+    pub fn start(config: AccessLogConfig) -> Result<Self> {
+        let mut output: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(RotatingFile::open(
+                path.clone(),
+                config.max_size,
+                config.max_files,
+            )?),
+            None => Box::new(io::stdout()),
+        };
+        let (lines, receiver) = sync_channel::<String>(QUEUE_LEN);
+        thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || {
+                for line in receiver {
+                    // In one write, so rotation never splits a line
+                    if let Err(err) = output
+                        .write_all(line.as_bytes())
+                        .and_then(|_| output.flush())
+                    {
+                        log::error!("Failed to write the access log: {:?}", err);
+                    }
+                }
+            })?;
+
+        Ok(AccessLog {
+            lines,
+            format: config.format,
+        })
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let mut line = entry.format(self.format);
+        line.push('\n');
+        match self.lines.try_send(line) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => log::warn!("Access log is behind, dropped a line"),
+            Err(TrySendError::Disconnected(_)) => log::error!("Access log writer stopped"),
+        }
+    }
+
+    /// Log `entry` once `body` is sent or dropped, with its size and the time it took.
+    pub fn wrap(&self, body: ProxyBody, entry: Entry, started: Instant) -> LoggedBody {
+        LoggedBody {
+            body,
+            entry,
+            started,
+            log: self.clone(),
+        }
+    }
+}
+
+/// A response body which writes its access log entry when done.
+pub struct LoggedBody {
+    body: ProxyBody,
+    entry: Entry,
+    started: Instant,
+    log: AccessLog,
+}
+
+impl Body for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let res = Pin::new(&mut this.body).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &res {
+            if let Some(data) = frame.data_ref() {
+                this.entry.bytes += data.len() as u64;
+            }
+        }
+        res
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        self.entry.latency_ms = self.started.elapsed().as_millis() as u64;
+        self.log.write(&self.entry);
+    }
+}
+
+/// A file which is moved aside once it reaches `max_size`.
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", self.path.display(), i));
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let from = rotated(i);
+                if from.exists() {
+                    fs::rename(from, rotated(i + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Seconds since the epoch as UTC date and time.
+fn civil(time: SystemTime) -> (i64, u32, u32, u64, u64, u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, millis) = civil(time);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}.{millis:03}Z")
+}
+
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second, _) = civil(time);
+    let month = MONTHS[month as usize - 1];
+    format!("{day:02}/{month}/{year:04}:{hour:02}:{minute:02}:{second:02} +0000")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{escape, AccessLogFormat, Entry};
+
+    #[test]
+    fn format_works() {
+        let entry = Entry {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            subdomain: "demo".to_string(),
+            client_ip: "192.0.2.1".parse().unwrap(),
+            method: "GET".to_string(),
+            path: "/a?b=c".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 512,
+            latency_ms: 12,
+            upgrade: false,
+            connection: Some("stream 3".to_string()),
+            referer: None,
+            user_agent: Some("curl/8.0".to_string()),
+        };
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            "192.0.2.1 - - [14/Nov/2023:22:13:20 +0000] \"GET /a?b=c HTTP/1.1\" 200 512 \"-\" \"curl/8.0\" demo 12ms upgrade=false stream 3"
+        );
+        assert_eq!(
+            entry.format(AccessLogFormat::Json),
+            r#"{"time":"2023-11-14T22:13:20.250Z","subdomain":"demo","client_ip":"192.0.2.1","method":"GET","path":"/a?b=c","protocol":"HTTP/1.1","status":200,"bytes":512,"latency_ms":12,"upgrade":false,"connection":"stream 3","referer":null,"user_agent":"curl/8.0"}"#
+        );
+    }
+
+    #[test]
+    fn escape_works() {
+        assert_eq!(escape("/a b"), "/a b");
+        assert_eq!(escape("\x1b[31m\x7f\n"), "\\x1b[31m\\x7f\\x0a");
+
+        // A forged end of the request field stays inside it
+        let entry = Entry {
+            time: UNIX_EPOCH,
+            subdomain: "demo".to_string(),
+            client_ip: "192.0.2.1".parse().unwrap(),
+            method: "GET".to_string(),
+            path: "/x\" 200 0 \"-\\".to_string(),
+            protocol: "HTTP/1.1".to_string(),
+            status: 404,
+            bytes: 0,
+            latency_ms: 1,
+            upgrade: false,
+            connection: None,
+            referer: None,
+            user_agent: None,
+        };
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            r#"192.0.2.1 - - [01/Jan/1970:00:00:00 +0000] "GET /x\" 200 0 \"-\\ HTTP/1.1" 404 0 "-" "-" demo 1ms upgrade=false -"#
+        );
+    }
+}
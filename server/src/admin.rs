@@ -0,0 +1,275 @@
+//! Admin api to watch and evict tunnels, authenticated with the `ADMIN_TOKEN` bearer token.
+
+use std::{
+    net::IpAddr,
+    sync::atomic::Ordering,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::{
+    delete, get,
+    http::header::{AUTHORIZATION, WWW_AUTHENTICATE},
+    put, web, HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api::validate_endpoint;
+use crate::handshake::constant_time_eq;
+use crate::state::{Client, State};
+use crate::types::TunnelType;
+use crate::CONFIG;
+
+#[derive(Debug, Serialize)]
+struct TunnelInfo {
+    id: String,
+    tunnel_type: TunnelType,
+    /// Address the tunnel was registered from.
+    client_ip: Option<IpAddr>,
+    /// Unix timestamps in seconds.
+    created_at: u64,
+    last_activity: u64,
+    /// Sent to the client, i.e. the requests of visitors.
+    bytes_sent: u64,
+    /// Received from the client, i.e. the responses of the local service.
+    bytes_received: u64,
+    connected_sockets: usize,
+    multiplexed: bool,
+    public_port: Option<u16>,
+}
+
+impl TunnelInfo {
+    async fn new(id: &str, client: &Client) -> Self {
+        let stats = client.stats().await;
+        TunnelInfo {
+            id: id.to_string(),
+            tunnel_type: client.tunnel_type,
+            client_ip: client.client_ip,
+            created_at: unix_secs(client.created_at),
+            last_activity: unix_secs(client.last_activity()),
+            bytes_sent: client.traffic.sent.load(Ordering::Relaxed),
+            bytes_received: client.traffic.received.load(Ordering::Relaxed),
+            connected_sockets: stats.connected_sockets,
+            multiplexed: stats.multiplexed,
+            public_port: client.public_port,
+        }
+    }
+}
+
+#[get("/api/admin/tunnels")]
+pub async fn list_tunnels(req: HttpRequest, state: web::Data<State>) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    let manager = state.manager.lock().await;
+    let mut tunnels = Vec::with_capacity(manager.clients.len());
+    for (id, client) in manager.clients.iter() {
+        let client = client.lock().await;
+        tunnels.push(TunnelInfo::new(id, &client).await);
+    }
+    tunnels.sort_by(|a, b| a.id.cmp(&b.id));
+
+    HttpResponse::Ok().json(tunnels)
+}
+
+#[get("/api/admin/tunnels/{id}")]
+pub async fn get_tunnel(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    let client = state.manager.lock().await.get_client(&id);
+    match client {
+        Some(client) => {
+            let client = client.lock().await;
+            HttpResponse::Ok().json(TunnelInfo::new(&id, &client).await)
+        }
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Disconnect a tunnel, its client may register it again unless it is blocked.
+#[delete("/api/admin/tunnels/{id}")]
+pub async fn kick_tunnel(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    let mut manager = state.manager.lock().await;
+    if manager.kick(&id).await {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[get("/api/admin/blocked")]
+pub async fn list_blocked(req: HttpRequest, state: web::Data<State>) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    let manager = state.manager.lock().await;
+    let mut blocked = manager.blocked.iter().cloned().collect::<Vec<_>>();
+    blocked.sort();
+
+    HttpResponse::Ok().json(blocked)
+}
+
+/// Block a subdomain, kicking the tunnel currently using it.
+/// Unlike reservations, blocks are only kept in memory and end with a restart.
+#[put("/api/admin/blocked/{id}")]
+pub async fn block_subdomain(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+    if !validate_endpoint(&id).unwrap_or_default() {
+        return HttpResponse::BadRequest().body("Invalid subdomain");
+    }
+
+    state.manager.lock().await.block(&id).await;
+    HttpResponse::NoContent().finish()
+}
+
+#[delete("/api/admin/blocked/{id}")]
+pub async fn unblock_subdomain(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    if state.manager.lock().await.unblock(&id) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ReservationInfo {
+    id: String,
+    /// Registers the subdomain when passed as the reclaim token.
+    token: String,
+    /// Unix timestamp in seconds.
+    created_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReserveRequest {
+    /// The owner token, generated if missing.
+    token: Option<String>,
+}
+
+#[get("/api/admin/reservations")]
+pub async fn list_reservations(req: HttpRequest, state: web::Data<State>) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    let manager = state.manager.lock().await;
+    let reservations = manager
+        .reservations
+        .iter()
+        .map(|(id, reservation)| ReservationInfo {
+            id: id.clone(),
+            token: reservation.token.clone(),
+            created_at: reservation.created_at,
+        })
+        .collect::<Vec<_>>();
+
+    HttpResponse::Ok().json(reservations)
+}
+
+/// Reserve a subdomain for the holder of a token, kicking any other tunnel using it.
+#[put("/api/admin/reservations/{id}")]
+pub async fn reserve_subdomain(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: Option<web::Json<ReserveRequest>>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+    if !validate_endpoint(&id).unwrap_or_default() {
+        return HttpResponse::BadRequest().body("Invalid subdomain");
+    }
+    let token = body.and_then(|body| body.into_inner().token);
+    if token.as_deref().is_some_and(str::is_empty) {
+        return HttpResponse::BadRequest().body("Empty token");
+    }
+
+    let mut manager = state.manager.lock().await;
+    match manager.reserve(&id, token).await {
+        Ok(reservation) => HttpResponse::Ok().json(ReservationInfo {
+            id: id.into_inner(),
+            token: reservation.token,
+            created_at: reservation.created_at,
+        }),
+        Err(err) => {
+            log::error!("Failed to reserve {}: {:?}", id, err);
+            HttpResponse::InternalServerError().body("Failed to save the reservation")
+        }
+    }
+}
+
+#[delete("/api/admin/reservations/{id}")]
+pub async fn release_subdomain(
+    req: HttpRequest,
+    id: web::Path<String>,
+    state: web::Data<State>,
+) -> impl Responder {
+    if let Err(denied) = authorize(&req) {
+        return denied;
+    }
+
+    match state.manager.lock().await.reservations.release(&id) {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(err) => {
+            log::error!("Failed to release {}: {:?}", id, err);
+            HttpResponse::InternalServerError().body("Failed to save the reservations")
+        }
+    }
+}
+
+/// Check the bearer token, the api doesn't exist without a configured token.
+pub(crate) fn authorize(req: &HttpRequest) -> Result<(), HttpResponse> {
+    let admin_token = match CONFIG.admin_token.as_deref() {
+        Some(token) if !token.is_empty() => token,
+        _ => return Err(HttpResponse::NotFound().finish()),
+    };
+
+    let presented = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(token) if constant_time_eq(token.as_bytes(), admin_token.as_bytes()) => Ok(()),
+        _ => Err(HttpResponse::Unauthorized()
+            .insert_header((WWW_AUTHENTICATE, "Bearer"))
+            .body("Invalid admin token")),
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
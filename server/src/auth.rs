@@ -1,39 +1,172 @@
+//! Decide which clients may register a tunnel, with a built-in [`AuthMode`] or an [`Auth`] of your own.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
 use anyhow::Result;
 use async_trait::async_trait;
 
+use crate::auth_cache::CachedAuth;
 use crate::error::ServerError;
-use crate::CONFIG;
+use crate::handshake::constant_time_eq;
+use crate::htpasswd::HtpasswdFile;
+use crate::jwt::{JwtAuth, JwtConfig, JwtKeySource};
+use crate::webhook::WebhookAuth;
+use crate::{AuthMode, CONFIG};
 
-#[async_trait]
-pub trait Auth {
-    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool>;
+/// How long decisions of remote backends are reused by default, denials for a shorter while
+/// so a fixed credential works soon.
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_NEGATIVE_CACHE_TTL: Duration = Duration::from_secs(5);
+/// Timeouts of the calls to remote backends, registrations fail with a server error after them.
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Shared by the remote backends, to reuse connections.
+    pub(crate) static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()
+        .expect("Failed to build the HTTP client");
+}
+
+/// A client asking to register a tunnel.
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub subdomain: String,
+    /// Address the registration came from, see [`crate::ServerConfig::api_proxy_protocol`].
+    pub client_ip: Option<IpAddr>,
+    /// The credential passed by the client, if any.
+    pub credential: Option<String>,
+}
+
+/// Whether a registration is allowed, with the limits of the tunnel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthDecision {
+    pub allowed: bool,
+    /// Sockets the client may open, only lowers the server's `max_sockets`.
+    pub max_sockets: Option<u8>,
+    /// Close the tunnel after this long, the client has to register again.
+    pub ttl: Option<Duration>,
+}
+
+impl From<bool> for AuthDecision {
+    fn from(allowed: bool) -> Self {
+        AuthDecision {
+            allowed,
+            ..Default::default()
+        }
+    }
 }
 
+/// Authorizes tunnel registrations, implement it with [`macro@async_trait`] to plug in your own checks.
+///
+/// ```ignore
+/// struct Directory;
+///
+/// #[async_trait]
+/// impl Auth for Directory {
+///     async fn authorize(&self, request: &AuthRequest) -> anyhow::Result<bool> {
+///         Ok(request.subdomain.starts_with("team-") && request.credential.is_some())
+///     }
+/// }
+/// ```
 #[async_trait]
-impl Auth for () {
-    async fn credential_is_valid(&self, _credential: &str, _value: &str) -> Result<bool> {
-        Ok(true)
+pub trait Auth: Send + Sync {
+    /// Whether the client may register the subdomain, errors are answered with a server error.
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool>;
+
+    /// Like [`Auth::authorize`], for backends which also limit the tunnel.
+    async fn decide(&self, request: &AuthRequest) -> Result<AuthDecision> {
+        Ok(self.authorize(request).await?.into())
     }
+
+    /// Name of the backend, as used in logs and metrics.
+    fn name(&self) -> &str {
+        "CUSTOM"
+    }
+}
+
+/// The backend of a built-in mode, reading its settings from the environment.
+pub async fn from_mode(mode: &AuthMode) -> Result<Arc<dyn Auth>> {
+    let auth: Arc<dyn Auth> = match mode {
+        AuthMode::NOAUTH => Arc::new(NoAuth),
+        AuthMode::CLOUDFLARE => Arc::new(cached(CfWorkerStore)),
+        AuthMode::PLAINTEXT => Arc::new(PlaintextPassword),
+        AuthMode::HTPASSWD => {
+            let path = CONFIG
+                .htpasswd_file
+                .as_ref()
+                .ok_or(ServerError::InvalidConfig)?;
+            HtpasswdFile::watch(path.into())?
+        }
+        AuthMode::JWT => JwtAuth::start(jwt_config(&CONFIG)?).await?,
+        AuthMode::WEBHOOK => {
+            let url = CONFIG
+                .auth_webhook_url
+                .clone()
+                .ok_or(ServerError::InvalidConfig)?;
+            Arc::new(cached(WebhookAuth::new(
+                url,
+                CONFIG.auth_webhook_token.clone(),
+            )))
+        }
+    };
+    Ok(auth)
+}
+
+/// Cache the decisions of a remote backend for `AUTH_CACHE_TTL` and `AUTH_NEGATIVE_CACHE_TTL` seconds.
+fn cached<A: Auth>(auth: A) -> CachedAuth<A> {
+    let ttl = CONFIG
+        .auth_cache_ttl
+        .map_or(DEFAULT_CACHE_TTL, Duration::from_secs);
+    let negative_ttl = CONFIG
+        .auth_negative_cache_ttl
+        .map_or(DEFAULT_NEGATIVE_CACHE_TTL, Duration::from_secs);
+    CachedAuth::new(auth, ttl, negative_ttl)
 }
 
+/// Lets every client in.
+pub struct NoAuth;
+/// Checks the credential against `PLAINTEXT_PASSWORD`.
 pub struct PlaintextPassword;
+/// Checks the credential against the value stored for the subdomain in a Cloudflare KV namespace.
 pub struct CfWorkerStore;
 
+#[async_trait]
+impl Auth for NoAuth {
+    async fn authorize(&self, _request: &AuthRequest) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn name(&self) -> &str {
+        "NOAUTH"
+    }
+}
+
 #[async_trait]
 impl Auth for PlaintextPassword {
-    async fn credential_is_valid(&self, credential: &str, _value: &str) -> Result<bool> {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
         let password = CONFIG
             .plaintext_password
             .as_ref()
             .ok_or(ServerError::InvalidConfig)?;
 
-        Ok(credential == password)
+        Ok(request.credential.as_ref() == Some(password))
+    }
+
+    fn name(&self) -> &str {
+        "PLAINTEXT"
     }
 }
 
 #[async_trait]
 impl Auth for CfWorkerStore {
-    async fn credential_is_valid(&self, credential: &str, value: &str) -> Result<bool> {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+        let Some(credential) = request.credential.as_deref() else {
+            return Ok(false);
+        };
+        let value = &request.subdomain;
         let account = CONFIG
             .cloudflare_account
             .as_ref()
@@ -51,8 +184,7 @@ impl Auth for CfWorkerStore {
             .as_ref()
             .ok_or(ServerError::InvalidConfig)?;
 
-        let client = reqwest::Client::new();
-        let resp = client.get(
+        let resp = HTTP_CLIENT.get(
             format!(
                 "https://api.cloudflare.com/client/v4/accounts/{}/storage/kv/namespaces/{}/values/{}",
                 account, namespace, value
@@ -60,12 +192,19 @@ impl Auth for CfWorkerStore {
             .header("X-Auth-Email", email)
             .header("X-Auth-Key", key)
             .send()
-            .await?
-            .text()
             .await?;
-        log::info!("{:#?}", resp);
+        // No value stored for the subdomain
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        // The body is the stored credential, keep it out of the logs
+        let stored = resp.error_for_status()?.text().await?;
+
+        Ok(constant_time_eq(credential.as_bytes(), stored.as_bytes()))
+    }
 
-        Ok(credential == resp)
+    fn name(&self) -> &str {
+        "CLOUDFLARE"
     }
 }
 
@@ -98,7 +237,47 @@ pub fn validate(mode: &crate::AuthMode, config: &crate::Config) -> Result<()> {
                 ));
             }
         }
+        crate::AuthMode::HTPASSWD => {
+            if config.htpasswd_file.is_none() {
+                return Err(anyhow::anyhow!("Missing HTPASSWD_FILE env var"));
+            }
+        }
+        crate::AuthMode::JWT => {
+            jwt_config(config)?;
+        }
+        crate::AuthMode::WEBHOOK => {
+            if config.auth_webhook_url.is_none() {
+                return Err(anyhow::anyhow!("Missing AUTH_WEBHOOK_URL env var"));
+            }
+        }
         crate::AuthMode::NOAUTH => {}
     }
     Ok(())
 }
+
+fn jwt_config(config: &crate::Config) -> Result<JwtConfig> {
+    let keys = match (&config.jwt_key_file, &config.jwt_jwks) {
+        (Some(path), None) => JwtKeySource::File(path.clone()),
+        (None, Some(location)) => JwtKeySource::Jwks(location.clone()),
+        (None, None) => return Err(anyhow::anyhow!("Missing JWT_KEY_FILE or JWT_JWKS env var")),
+        (Some(_), Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "Set either JWT_KEY_FILE or JWT_JWKS, not both"
+            ))
+        }
+    };
+    let algorithm = config
+        .jwt_algorithm
+        .as_deref()
+        .unwrap_or("RS256")
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Unsupported JWT_ALGORITHM"))?;
+
+    Ok(JwtConfig {
+        keys,
+        algorithm,
+        claim: config.jwt_claim.clone(),
+        issuer: config.jwt_issuer.clone(),
+        audience: config.jwt_audience.clone(),
+    })
+}
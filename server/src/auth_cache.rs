@@ -0,0 +1,165 @@
+//! Remembers the decisions of a remote [`Auth`] for a while, so reconnecting clients don't hit it every time.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use ring::digest::{digest, SHA256};
+
+use crate::auth::{Auth, AuthDecision, AuthRequest};
+
+/// Decisions kept at most, expired ones are dropped to make room.
+const MAX_ENTRIES: usize = 10_000;
+
+/// Caches allowing decisions of `inner` for `ttl` and denying ones for `negative_ttl`,
+/// zero doesn't cache them. Errors aren't cached.
+pub struct CachedAuth<A> {
+    inner: A,
+    ttl: Duration,
+    negative_ttl: Duration,
+    /// By a digest of the request, to keep credentials out of memory.
+    entries: Mutex<HashMap<[u8; 32], (Instant, AuthDecision)>>,
+}
+
+impl<A: Auth> CachedAuth<A> {
+    pub fn new(inner: A, ttl: Duration, negative_ttl: Duration) -> Self {
+        CachedAuth {
+            inner,
+            ttl,
+            negative_ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lookup(&self, key: &[u8; 32]) -> Option<AuthDecision> {
+        let entries = self.entries.lock().expect("Cache lock poisoned");
+        match entries.get(key) {
+            Some((expires_at, decision)) if *expires_at > Instant::now() => Some(decision.clone()),
+            _ => None,
+        }
+    }
+
+    fn store(&self, key: [u8; 32], decision: AuthDecision) {
+        let ttl = if decision.allowed {
+            self.ttl
+        } else {
+            self.negative_ttl
+        };
+        if ttl.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("Cache lock poisoned");
+        if entries.len() >= MAX_ENTRIES {
+            entries.retain(|_, (expires_at, _)| *expires_at > now);
+            if entries.len() >= MAX_ENTRIES {
+                return;
+            }
+        }
+        entries.insert(key, (now + ttl, decision));
+    }
+}
+
+#[async_trait]
+impl<A: Auth> Auth for CachedAuth<A> {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+        Ok(self.decide(request).await?.allowed)
+    }
+
+    async fn decide(&self, request: &AuthRequest) -> Result<AuthDecision> {
+        let key = cache_key(request);
+        if let Some(decision) = self.lookup(&key) {
+            return Ok(decision);
+        }
+        let decision = self.inner.decide(request).await?;
+        self.store(key, decision.clone());
+        Ok(decision)
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+fn cache_key(request: &AuthRequest) -> [u8; 32] {
+    // Length prefixed, so no two requests share the input
+    let mut input = Vec::new();
+    let client_ip = request.client_ip.map(|ip| ip.to_string());
+    for field in [
+        Some(request.subdomain.as_str()),
+        request.credential.as_deref(),
+        client_ip.as_deref(),
+    ] {
+        match field {
+            Some(field) => {
+                input.push(1);
+                input.extend_from_slice(&(field.len() as u64).to_be_bytes());
+                input.extend_from_slice(field.as_bytes());
+            }
+            None => input.push(0),
+        }
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(digest(&SHA256, &input).as_ref());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use async_trait::async_trait;
+
+    use super::CachedAuth;
+    use crate::auth::{Auth, AuthRequest};
+
+    /// Allows `letmein`, counting the calls.
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+
+    #[async_trait]
+    impl Auth for Counting {
+        async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(request.credential.as_deref() == Some("letmein"))
+        }
+    }
+
+    #[tokio::test]
+    async fn decisions_are_cached() {
+        let auth = CachedAuth::new(
+            Counting::default(),
+            Duration::from_millis(200),
+            Duration::from_millis(100),
+        );
+        let request = |credential: &str| AuthRequest {
+            subdomain: "demo".to_string(),
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            credential: Some(credential.to_string()),
+        };
+
+        assert!(auth.decide(&request("letmein")).await.unwrap().allowed);
+        assert!(auth.decide(&request("letmein")).await.unwrap().allowed);
+        assert!(!auth.decide(&request("guess")).await.unwrap().allowed);
+        assert!(!auth.decide(&request("guess")).await.unwrap().allowed);
+        assert_eq!(auth.inner.0.load(Ordering::SeqCst), 2);
+
+        // Denials are forgotten sooner
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(auth.decide(&request("letmein")).await.unwrap().allowed);
+        assert!(!auth.decide(&request("guess")).await.unwrap().allowed);
+        assert_eq!(auth.inner.0.load(Ordering::SeqCst), 3);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(auth.decide(&request("letmein")).await.unwrap().allowed);
+        assert_eq!(auth.inner.0.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,98 @@
+//! Turns away addresses which keep failing authentication, before they reach the auth backend.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Addresses tracked at most, those whose window is over are dropped to make room.
+const MAX_TRACKED: usize = 10_000;
+
+struct Failures {
+    /// When the first failure of the current window happened.
+    since: Instant,
+    count: u32,
+}
+
+/// Allows `max_failures` failed registrations per address within `window`, zero allows any.
+pub struct FailureLimiter {
+    max_failures: u32,
+    window: Duration,
+    failures: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+impl FailureLimiter {
+    pub fn new(max_failures: u32, window: Duration) -> Self {
+        FailureLimiter {
+            max_failures,
+            window,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long `ip` has to wait before trying again, `None` if it may try now.
+    pub fn blocked_for(&self, ip: IpAddr) -> Option<Duration> {
+        if self.max_failures == 0 {
+            return None;
+        }
+        let failures = self.failures.lock().expect("Failures lock poisoned");
+        let entry = failures.get(&ip)?;
+        let elapsed = entry.since.elapsed();
+        if entry.count >= self.max_failures && elapsed < self.window {
+            Some(self.window - elapsed)
+        } else {
+            None
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.max_failures == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut failures = self.failures.lock().expect("Failures lock poisoned");
+        if failures.len() >= MAX_TRACKED && !failures.contains_key(&ip) {
+            failures.retain(|_, entry| now.duration_since(entry.since) < self.window);
+        }
+        let entry = failures.entry(ip).or_insert(Failures {
+            since: now,
+            count: 0,
+        });
+        if now.duration_since(entry.since) >= self.window {
+            entry.since = now;
+            entry.count = 0;
+        }
+        entry.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::FailureLimiter;
+
+    #[test]
+    fn failures_are_limited() {
+        let limiter = FailureLimiter::new(2, Duration::from_millis(100));
+        let ip = "192.0.2.1".parse().unwrap();
+        let other = "192.0.2.2".parse().unwrap();
+
+        limiter.record_failure(ip);
+        assert!(limiter.blocked_for(ip).is_none());
+        limiter.record_failure(ip);
+        assert!(limiter.blocked_for(ip).is_some());
+        assert!(limiter.blocked_for(other).is_none());
+
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.blocked_for(ip).is_none());
+        limiter.record_failure(ip);
+        assert!(limiter.blocked_for(ip).is_none());
+
+        let unlimited = FailureLimiter::new(0, Duration::from_secs(60));
+        unlimited.record_failure(ip);
+        assert!(unlimited.blocked_for(ip).is_none());
+    }
+}
@@ -1,3 +1,4 @@
+use dotenvy::dotenv;
 use serde::Deserialize;
 
 #[derive(Deserialize, Debug, Default)]
@@ -9,4 +10,62 @@ pub struct Config {
     pub cloudflare_auth_key: Option<String>,
     // Plaintext password
     pub plaintext_password: Option<String>,
+    // Htpasswd file with a bcrypt hash per user
+    pub htpasswd_file: Option<String>,
+    // JWT verification, with a key file or a JWKS document
+    pub jwt_key_file: Option<String>,
+    pub jwt_jwks: Option<String>,
+    pub jwt_algorithm: Option<String>,
+    pub jwt_claim: Option<String>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    // Auth webhook, with the bearer token it expects
+    pub auth_webhook_url: Option<String>,
+    pub auth_webhook_token: Option<String>,
+    // Seconds decisions of the Cloudflare and webhook backends are cached, allowing and denying ones
+    pub auth_cache_ttl: Option<u64>,
+    pub auth_negative_cache_ttl: Option<u64>,
+    // Bearer token of the admin api, the api is disabled without it
+    pub admin_token: Option<String>,
+}
+
+impl Config {
+    /// Read the config from the environment, with the variables of a `.env` file added.
+    pub fn from_env() -> envy::Result<Self> {
+        dotenv().ok();
+        Self::from_vars(std::env::vars())
+    }
+
+    /// Read the config from environment variables, where an empty one like `JWT_JWKS=` counts as unset.
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> envy::Result<Self> {
+        envy::from_iter(vars.into_iter().filter(|(_, value)| !value.is_empty()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn empty_vars_are_unset() {
+        let vars = [
+            ("JWT_KEY_FILE", ""),
+            ("JWT_JWKS", "https://example.com/jwks.json"),
+            ("AUTH_CACHE_TTL", ""),
+            ("AUTH_NEGATIVE_CACHE_TTL", "5"),
+        ];
+        let config =
+            Config::from_vars(vars.map(|(name, value)| (name.to_string(), value.to_string())))
+                .unwrap();
+        assert_eq!(config.jwt_key_file, None);
+        assert_eq!(
+            config.jwt_jwks.as_deref(),
+            Some("https://example.com/jwks.json")
+        );
+        assert_eq!(config.auth_cache_ttl, None);
+        assert_eq!(config.auth_negative_cache_ttl, Some(5));
+
+        let malformed = [("AUTH_CACHE_TTL".to_string(), "5m".to_string())];
+        assert!(Config::from_vars(malformed).is_err());
+    }
 }
@@ -16,4 +16,40 @@ pub enum ServerError {
     InvalidHostName,
     #[error("Server config is not valid")]
     InvalidConfig,
+    #[error("Subdomain is in use by another client")]
+    SubdomainInUse,
+    #[error("Tunnel socket failed to authenticate")]
+    SocketAuthFailed,
+    #[error("Subdomain is blocked")]
+    SubdomainBlocked,
+    #[error("Tunnel only accepts raw TCP connections on its own port")]
+    NotHttpTunnel,
+    #[error("Tunnel client did not respond in time")]
+    UpstreamTimeout,
+    #[error("No tunnel connection became available in time")]
+    TunnelBusy,
+    #[error("Subdomain is reserved")]
+    SubdomainReserved,
+}
+
+impl ServerError {
+    /// Name of the variant, as used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerError::NoHostHeader => "NoHostHeader",
+            ServerError::ProxyNotReady => "ProxyNotReady",
+            ServerError::EmptyConnection => "EmptyConnection",
+            ServerError::NoUpgradeExtension => "NoUpgradeExtension",
+            ServerError::NoUpgradeHeader => "NoUpgradeHeader",
+            ServerError::InvalidHostName => "InvalidHostName",
+            ServerError::InvalidConfig => "InvalidConfig",
+            ServerError::SubdomainInUse => "SubdomainInUse",
+            ServerError::SocketAuthFailed => "SocketAuthFailed",
+            ServerError::SubdomainBlocked => "SubdomainBlocked",
+            ServerError::NotHttpTunnel => "NotHttpTunnel",
+            ServerError::UpstreamTimeout => "UpstreamTimeout",
+            ServerError::TunnelBusy => "TunnelBusy",
+            ServerError::SubdomainReserved => "SubdomainReserved",
+        }
+    }
 }
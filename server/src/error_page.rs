@@ -0,0 +1,201 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{Context, Result};
+use hyper::{
+    header::{HeaderMap, ACCEPT, CONTENT_TYPE},
+    Response, StatusCode,
+};
+use serde::Serialize;
+
+use crate::error::ServerError;
+use crate::proxy::{full, ProxyBody};
+
+const DEFAULT_PAGE: &str = include_str!("error_page.html");
+
+/// Pages served to visitors when a request can't be proxied, by status code.
+///
+/// Pages are HTML templates, `{{status}}`, `{{reason}}` and `{{message}}` are replaced
+/// with the details of the error. Statuses without a page of their own use a built-in one.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages {
+    pages: HashMap<u16, String>,
+}
+
+impl ErrorPages {
+    /// Load every `<status>.html` file of a directory, e.g. `404.html` and `502.html`.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let mut pages = ErrorPages::default();
+        let entries = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read error pages from {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let status = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".html"))
+                .and_then(|status| status.parse::<u16>().ok());
+            if let Some(status) = status {
+                let page = fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read error page {}", path.display()))?;
+                pages = pages.with_page(status, page);
+            }
+        }
+        Ok(pages)
+    }
+
+    /// Use `html` as the template for `status`.
+    pub fn with_page(mut self, status: u16, html: impl Into<String>) -> Self {
+        self.pages.insert(status, html.into());
+        self
+    }
+
+    /// The response for a failed proxy request, as JSON if the visitor asked for it.
+    pub(crate) fn respond(
+        &self,
+        err: &anyhow::Error,
+        request_headers: &HeaderMap,
+    ) -> Response<ProxyBody> {
+        let failure = Failure::from(err);
+        let status = failure.status;
+
+        let (content_type, body) = if accepts_json(request_headers) {
+            let body = serde_json::to_string(&failure).expect("Serializable error");
+            ("application/json", body)
+        } else {
+            let page = self
+                .pages
+                .get(&status.as_u16())
+                .map(String::as_str)
+                .unwrap_or(DEFAULT_PAGE);
+            let body = page
+                .replace("{{status}}", status.as_str())
+                .replace("{{reason}}", status.canonical_reason().unwrap_or_default())
+                .replace("{{message}}", failure.message);
+            ("text/html; charset=utf-8", body)
+        };
+
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, content_type)
+            .body(full(body))
+            .expect("Valid error response")
+    }
+}
+
+/// What visitors are told about a failed request.
+#[derive(Debug, Serialize)]
+struct Failure {
+    #[serde(serialize_with = "serialize_status")]
+    status: StatusCode,
+    error: &'static str,
+    message: &'static str,
+}
+
+impl From<&anyhow::Error> for Failure {
+    fn from(err: &anyhow::Error) -> Self {
+        let (status, error, message) = match err.downcast_ref::<ServerError>() {
+            Some(ServerError::ProxyNotReady | ServerError::NotHttpTunnel) => (
+                StatusCode::NOT_FOUND,
+                "tunnel_not_found",
+                "There is no tunnel at this address.",
+            ),
+            Some(ServerError::EmptyConnection) => (
+                StatusCode::BAD_GATEWAY,
+                "tunnel_unavailable",
+                "The tunnel client is not connected right now, try again later.",
+            ),
+            Some(ServerError::TunnelBusy) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "tunnel_busy",
+                "All connections of the tunnel are busy, try again later.",
+            ),
+            Some(ServerError::UpstreamTimeout) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                "upstream_timeout",
+                "The service behind the tunnel did not respond in time.",
+            ),
+            Some(ServerError::NoHostHeader | ServerError::InvalidHostName) => (
+                StatusCode::BAD_REQUEST,
+                "invalid_host",
+                "The request has no valid Host header.",
+            ),
+            Some(ServerError::NoUpgradeHeader) => (
+                StatusCode::BAD_GATEWAY,
+                "invalid_upgrade",
+                "The service behind the tunnel answered the upgrade without an Upgrade header.",
+            ),
+            Some(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "The request could not be proxied.",
+            ),
+            // Failures talking to the client, e.g. the connection closed midway
+            None if err.downcast_ref::<hyper::Error>().is_some() => (
+                StatusCode::BAD_GATEWAY,
+                "upstream_error",
+                "The service behind the tunnel closed the connection.",
+            ),
+            None if err.downcast_ref::<hyper::header::ToStrError>().is_some() => (
+                StatusCode::BAD_REQUEST,
+                "invalid_host",
+                "The request has no valid Host header.",
+            ),
+            None => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "The request could not be proxied.",
+            ),
+        };
+        Failure {
+            status,
+            error,
+            message,
+        }
+    }
+}
+
+fn serialize_status<S: serde::Serializer>(
+    status: &StatusCode,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u16(status.as_u16())
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("application/json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{
+        header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE},
+        StatusCode,
+    };
+
+    use super::ErrorPages;
+    use crate::error::ServerError;
+
+    #[test]
+    fn respond_works() {
+        let pages = ErrorPages::default().with_page(404, "<h1>{{status}} {{reason}}</h1>");
+        let not_found = anyhow::Error::from(ServerError::ProxyNotReady);
+        let response = pages.respond(&not_found, &HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/html; charset=utf-8");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let timeout = anyhow::Error::from(ServerError::UpstreamTimeout);
+        let response = pages.respond(&timeout, &headers);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/json");
+
+        let response = pages.respond(&ServerError::EmptyConnection.into(), &HeaderMap::new());
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+
+use crate::error::ServerError;
+
+/// Clients prove they own a tunnel by sending `LT-AUTH <socket token>\n` on every new socket.
+const AUTH_PREFIX: &str = "LT-AUTH ";
+/// Same as [`AUTH_PREFIX`], but the socket then carries a multiplexed session instead of a single connection.
+const MUX_PREFIX: &str = "LT-MUX ";
+const MAX_PREAMBLE_LEN: usize = 128;
+/// How long a client has to send the preamble.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Legacy clients send nothing until the first request, a silent socket is taken as one.
+const LEGACY_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Debug, PartialEq)]
+pub enum Handshake {
+    Authenticated,
+    /// An authenticated socket to run a multiplexed session on.
+    Multiplexed,
+    /// A client which doesn't know the handshake, only accepted in compatibility mode.
+    Legacy,
+}
+
+/// Check that a new tunnel socket belongs to the client owning `token`.
+pub async fn authenticate(
+    socket: &mut TcpStream,
+    token: &str,
+    allow_legacy: bool,
+) -> Result<Handshake> {
+    if allow_legacy {
+        let mut buf = [0u8; 1];
+        if timeout(LEGACY_WAIT, socket.peek(&mut buf)).await.is_err() {
+            return Ok(Handshake::Legacy);
+        }
+    }
+
+    let line = timeout(HANDSHAKE_TIMEOUT, read_line(socket))
+        .await
+        .map_err(|_| ServerError::SocketAuthFailed)??;
+    let (handshake, presented) = if let Some(presented) = line.strip_prefix(AUTH_PREFIX) {
+        (Handshake::Authenticated, presented)
+    } else if let Some(presented) = line.strip_prefix(MUX_PREFIX) {
+        (Handshake::Multiplexed, presented)
+    } else {
+        return Err(ServerError::SocketAuthFailed.into());
+    };
+
+    if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+        Ok(handshake)
+    } else {
+        Err(ServerError::SocketAuthFailed.into())
+    }
+}
+
+/// Read up to a newline, one byte at a time so nothing after the preamble is consumed.
+async fn read_line(socket: &mut TcpStream) -> Result<String> {
+    let mut line = Vec::new();
+    loop {
+        let byte = socket.read_u8().await?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() >= MAX_PREAMBLE_LEN {
+            return Err(ServerError::SocketAuthFailed.into());
+        }
+        line.push(byte);
+    }
+    Ok(String::from_utf8(line)?)
+}
+
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{authenticate, Handshake};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    async fn handshake(preamble: Option<&'static [u8]>, allow_legacy: bool) -> Option<Handshake> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            if let Some(preamble) = preamble {
+                stream.write_all(preamble).await.unwrap();
+            }
+            // Keep the socket open until the server is done
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        });
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let res = authenticate(&mut socket, "secret", allow_legacy).await;
+        client.abort();
+        res.ok()
+    }
+
+    #[tokio::test]
+    async fn authenticate_works() {
+        use Handshake::*;
+
+        assert_eq!(
+            handshake(Some(b"LT-AUTH secret\n"), false).await,
+            Some(Authenticated)
+        );
+        assert_eq!(
+            handshake(Some(b"LT-MUX secret\n"), false).await,
+            Some(Multiplexed)
+        );
+        assert_eq!(handshake(Some(b"LT-AUTH wrong\n"), false).await, None);
+        assert_eq!(handshake(Some(b"LT-MUX wrong\n"), false).await, None);
+        assert_eq!(handshake(Some(b"GET / HTTP/1.1\r\n"), false).await, None);
+        assert_eq!(handshake(None, false).await, None);
+
+        assert_eq!(handshake(None, true).await, Some(Legacy));
+        assert_eq!(
+            handshake(Some(b"LT-AUTH secret\n"), true).await,
+            Some(Authenticated)
+        );
+        assert_eq!(handshake(Some(b"LT-AUTH wrong\n"), true).await, None);
+    }
+}
@@ -0,0 +1,249 @@
+//! Per-user passwords from an htpasswd file of bcrypt hashes, reloaded when it changes.
+//!
+//! Each line reads `user:hash` with an optional third field listing the subdomains the user
+//! may register, e.g. `alice:$2y$10$...:alice-*,billing-dev`. Clients pass `user:password` as credential.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+
+use crate::auth::{Auth, AuthRequest};
+
+/// How often the file is checked for changes, short so removed users are locked out quickly.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Verified in place of the hash of an unknown user, so they take as long to turn away as a wrong password.
+const DUMMY_HASH: &str = "$2b$10$n4lOVLVdiyLFHtHu9gYfqOSMQSzgZBcztMoE2MaxysFD1lz1xrY4y";
+
+#[derive(Debug)]
+struct User {
+    hash: String,
+    /// Subdomain patterns where `*` matches any characters, any subdomain when empty.
+    subdomains: Vec<String>,
+}
+
+impl User {
+    fn allows(&self, subdomain: &str) -> bool {
+        self.subdomains.is_empty()
+            || self
+                .subdomains
+                .iter()
+                .any(|pattern| matches_pattern(pattern, subdomain))
+    }
+}
+
+/// Checks `user:password` credentials against an htpasswd file.
+pub struct HtpasswdFile {
+    path: PathBuf,
+    users: RwLock<Arc<HashMap<String, User>>>,
+}
+
+impl HtpasswdFile {
+    /// Load the file and keep reloading it whenever it changes.
+    pub fn watch(path: PathBuf) -> Result<Arc<Self>> {
+        let users = load(&path)?;
+        log::info!("Loaded {} users from {}", users.len(), path.display());
+        let file = Arc::new(HtpasswdFile {
+            path,
+            users: RwLock::new(Arc::new(users)),
+        });
+        tokio::spawn(reload(file.clone()));
+        Ok(file)
+    }
+}
+
+#[async_trait]
+impl Auth for HtpasswdFile {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+        let Some((name, password)) = request
+            .credential
+            .as_deref()
+            .and_then(|credential| credential.split_once(':'))
+        else {
+            return Ok(false);
+        };
+        let users = self.users.read().expect("Users lock poisoned").clone();
+        let user = users.get(name);
+        let allowed = match user {
+            Some(user) if !user.allows(&request.subdomain) => {
+                log::info!("User {} may not register {}", name, request.subdomain);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        };
+
+        // Verifying takes a while on purpose, keep it off the runtime threads
+        let password = password.to_string();
+        let hash = user.map_or(DUMMY_HASH, |user| &user.hash).to_string();
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash)).await??;
+        Ok(allowed && valid)
+    }
+
+    fn name(&self) -> &str {
+        "HTPASSWD"
+    }
+}
+
+/// Poll the modification time of the file, a failed reload keeps the previous users.
+async fn reload(file: Arc<HtpasswdFile>) {
+    let mut loaded = modified_at(&file.path);
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified_at(&file.path);
+        if modified == loaded {
+            continue;
+        }
+
+        match load(&file.path) {
+            Ok(users) => {
+                log::info!(
+                    "Reloaded {} users from {}",
+                    users.len(),
+                    file.path.display()
+                );
+                *file.users.write().expect("Users lock poisoned") = Arc::new(users);
+                loaded = modified;
+            }
+            Err(err) => log::warn!("Failed to reload {}: {:?}", file.path.display(), err),
+        }
+    }
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    path.metadata().and_then(|m| m.modified()).ok()
+}
+
+fn load(path: &Path) -> Result<HashMap<String, User>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    parse(&content).with_context(|| format!("Invalid htpasswd file {}", path.display()))
+}
+
+fn parse(content: &str) -> Result<HashMap<String, User>> {
+    let mut users = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(3, ':');
+        let (Some(name), Some(hash)) = (fields.next(), fields.next()) else {
+            bail!("Line {} isn't `user:hash`", number + 1);
+        };
+        if !hash.starts_with("$2") {
+            bail!("Line {}: only bcrypt hashes are supported", number + 1);
+        }
+        let subdomains = fields
+            .next()
+            .map(|patterns| {
+                patterns
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|pattern| !pattern.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let user = User {
+            hash: hash.to_string(),
+            subdomains,
+        };
+        if users.insert(name.to_string(), user).is_some() {
+            bail!("Line {}: user {} is listed twice", number + 1, name);
+        }
+    }
+    Ok(users)
+}
+
+/// Match `value` against a pattern where `*` stands for any run of characters.
+pub(crate) fn matches_pattern(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    // Without a `*` the whole value has to match
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches_pattern, parse, HtpasswdFile};
+    use crate::auth::{Auth, AuthRequest};
+
+    #[tokio::test]
+    async fn authorize_works() {
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let content = format!("# team\nalice:{hash}\nbob:{hash}:bob-*, demo\n");
+        let file = HtpasswdFile {
+            path: Default::default(),
+            users: std::sync::RwLock::new(std::sync::Arc::new(parse(&content).unwrap())),
+        };
+        let request = |subdomain: &str, credential: &str| AuthRequest {
+            subdomain: subdomain.to_string(),
+            client_ip: None,
+            credential: Some(credential.to_string()),
+        };
+
+        assert!(file
+            .authorize(&request("anything", "alice:secret"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("anything", "alice:guess"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("anything", "carol:secret"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("anything", "carol:localtunnel"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("anything", "secret"))
+            .await
+            .unwrap());
+        assert!(file
+            .authorize(&request("bob-api", "bob:secret"))
+            .await
+            .unwrap());
+        assert!(file
+            .authorize(&request("demo", "bob:secret"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("demo-2", "bob:secret"))
+            .await
+            .unwrap());
+        assert!(!file
+            .authorize(&request("alice-api", "bob:secret"))
+            .await
+            .unwrap());
+
+        assert!(matches_pattern("*-dev", "billing-dev"));
+        assert!(matches_pattern("a*b*c", "abc"));
+        assert!(!matches_pattern("a*a", "a"));
+        assert!(parse("alice:plain").is_err());
+        assert!(parse("# nobody yet\n").unwrap().is_empty());
+    }
+}
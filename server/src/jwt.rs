@@ -0,0 +1,307 @@
+//! Signed tokens issued elsewhere, e.g. by an internal portal, passed by clients as credential.
+//!
+//! A token is accepted when its signature, `exp` and `nbf` check out and its subdomain claim,
+//! `sub` by default, matches the requested subdomain. The claim may hold a pattern like `alice-*`,
+//! a custom claim also a list of them.
+
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+use crate::auth::{Auth, AuthRequest, HTTP_CLIENT};
+use crate::htpasswd::matches_pattern;
+
+/// How often the keys are loaded again, picking up rotated keys of a JWKS document.
+const KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_CLAIM: &str = "sub";
+
+/// Where the verification keys come from.
+#[derive(Debug, Clone)]
+pub enum JwtKeySource {
+    /// The shared secret for HMAC algorithms, a PEM public key otherwise.
+    File(String),
+    /// A JWKS document, a local path or an `http(s)://` url.
+    Jwks(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub keys: JwtKeySource,
+    /// The only algorithm accepted, tokens signed with any other are refused.
+    pub algorithm: Algorithm,
+    /// Claim matched against the requested subdomain, `sub` when not set.
+    pub claim: Option<String>,
+    pub issuer: Option<String>,
+    /// Required for tokens carrying an `aud` claim.
+    pub audience: Option<String>,
+}
+
+#[derive(Default)]
+struct Keys {
+    /// For tokens without a `kid`, or with a key id the document doesn't list.
+    default: Option<DecodingKey>,
+    by_id: HashMap<String, DecodingKey>,
+}
+
+/// Verifies JWTs with a key from a file or a JWKS document.
+pub struct JwtAuth {
+    config: JwtConfig,
+    validation: Validation,
+    keys: RwLock<Arc<Keys>>,
+}
+
+impl JwtAuth {
+    /// Load the keys and keep refreshing them.
+    pub async fn start(config: JwtConfig) -> Result<Arc<Self>> {
+        let keys = load(&config).await?;
+        let mut validation = Validation::new(config.algorithm);
+        validation.validate_nbf = true;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &config.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let auth = Arc::new(JwtAuth {
+            config,
+            validation,
+            keys: RwLock::new(Arc::new(keys)),
+        });
+        tokio::spawn(refresh(auth.clone()));
+        Ok(auth)
+    }
+
+    fn claim(&self) -> &str {
+        self.config.claim.as_deref().unwrap_or(DEFAULT_CLAIM)
+    }
+}
+
+#[async_trait]
+impl Auth for JwtAuth {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+        let Some(token) = request.credential.as_deref() else {
+            return Ok(false);
+        };
+        let header = match jsonwebtoken::decode_header(token) {
+            Ok(header) => header,
+            Err(err) => {
+                log::info!("Refused a malformed token: {}", err);
+                return Ok(false);
+            }
+        };
+        let keys = self.keys.read().expect("Keys lock poisoned").clone();
+        let key = header
+            .kid
+            .as_ref()
+            .and_then(|kid| keys.by_id.get(kid))
+            .or(keys.default.as_ref());
+        let Some(key) = key else {
+            log::info!("Refused a token signed with unknown key {:?}", header.kid);
+            return Ok(false);
+        };
+
+        let claims = match jsonwebtoken::decode::<Map<String, Value>>(token, key, &self.validation)
+        {
+            Ok(data) => data.claims,
+            Err(err) => {
+                log::info!("Refused a token for {}: {}", request.subdomain, err);
+                return Ok(false);
+            }
+        };
+        let allowed = match claims.get(self.claim()) {
+            Some(Value::String(pattern)) => matches_pattern(pattern, &request.subdomain),
+            Some(Value::Array(patterns)) => patterns.iter().any(|pattern| {
+                pattern
+                    .as_str()
+                    .is_some_and(|pattern| matches_pattern(pattern, &request.subdomain))
+            }),
+            _ => false,
+        };
+        if !allowed {
+            log::info!(
+                "Token doesn't grant {}, see its {} claim",
+                request.subdomain,
+                self.claim()
+            );
+        }
+        Ok(allowed)
+    }
+
+    fn name(&self) -> &str {
+        "JWT"
+    }
+}
+
+/// A failed refresh keeps the previous keys.
+async fn refresh(auth: Arc<JwtAuth>) {
+    let mut interval = tokio::time::interval(KEYS_REFRESH_INTERVAL);
+    // The first tick completes at once, the keys were just loaded
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match load(&auth.config).await {
+            Ok(keys) => *auth.keys.write().expect("Keys lock poisoned") = Arc::new(keys),
+            Err(err) => log::warn!("Failed to refresh the JWT keys: {:?}", err),
+        }
+    }
+}
+
+async fn load(config: &JwtConfig) -> Result<Keys> {
+    match &config.keys {
+        JwtKeySource::File(path) => {
+            let content = fs::read(path).with_context(|| format!("Failed to read {}", path))?;
+            let key = decoding_key(config.algorithm, &content)
+                .with_context(|| format!("Invalid JWT key in {}", path))?;
+            Ok(Keys {
+                default: Some(key),
+                by_id: HashMap::new(),
+            })
+        }
+        JwtKeySource::Jwks(location) => {
+            let content = if location.starts_with("http://") || location.starts_with("https://") {
+                HTTP_CLIENT
+                    .get(location)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .text()
+                    .await
+                    .with_context(|| format!("Failed to fetch {}", location))?
+            } else {
+                fs::read_to_string(location)
+                    .with_context(|| format!("Failed to read {}", location))?
+            };
+            let set: JwkSet = serde_json::from_str(&content)
+                .with_context(|| format!("Invalid JWKS document {}", location))?;
+            keys_of(&set)
+        }
+    }
+}
+
+fn decoding_key(algorithm: Algorithm, content: &[u8]) -> Result<DecodingKey> {
+    let key = match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            // Editors like to end files with a newline, it's never part of the secret
+            let secret = content.strip_suffix(b"\n").unwrap_or(content);
+            let secret = secret.strip_suffix(b"\r").unwrap_or(secret);
+            if secret.is_empty() {
+                bail!("Empty secret");
+            }
+            DecodingKey::from_secret(secret)
+        }
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => DecodingKey::from_rsa_pem(content)?,
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(content)?,
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(content)?,
+    };
+    Ok(key)
+}
+
+fn keys_of(set: &JwkSet) -> Result<Keys> {
+    let mut keys = Keys::default();
+    for jwk in &set.keys {
+        let key = DecodingKey::from_jwk(jwk)?;
+        match &jwk.common.key_id {
+            Some(kid) => {
+                keys.by_id.insert(kid.clone(), key);
+            }
+            None => keys.default = Some(key),
+        }
+    }
+    // Tokens without a `kid` are fine as long as there's no doubt about the key
+    if keys.default.is_none() && set.keys.len() == 1 {
+        keys.default = keys.by_id.values().next().cloned();
+    }
+    if keys.default.is_none() && keys.by_id.is_empty() {
+        return Err(anyhow!("JWKS document without keys"));
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, RwLock},
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header, Validation};
+    use serde_json::json;
+
+    use super::{decoding_key, JwtAuth, JwtConfig, JwtKeySource, Keys};
+    use crate::auth::{Auth, AuthRequest};
+
+    #[tokio::test]
+    async fn authorize_works() {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.validate_nbf = true;
+        let auth = JwtAuth {
+            config: JwtConfig {
+                keys: JwtKeySource::File(String::new()),
+                algorithm: Algorithm::HS256,
+                claim: Some("tunnels".to_string()),
+                issuer: None,
+                audience: None,
+            },
+            validation,
+            keys: RwLock::new(Arc::new(Keys {
+                default: Some(decoding_key(Algorithm::HS256, b"secret\n").unwrap()),
+                ..Default::default()
+            })),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let token = |claims: serde_json::Value, secret: &[u8]| {
+            encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(secret),
+            )
+            .unwrap()
+        };
+        let request = |subdomain: &str, token: String| AuthRequest {
+            subdomain: subdomain.to_string(),
+            client_ip: None,
+            credential: Some(token),
+        };
+
+        let valid = token(json!({"tunnels": "alice-*", "exp": now + 600}), b"secret");
+        assert!(auth
+            .authorize(&request("alice-api", valid.clone()))
+            .await
+            .unwrap());
+        assert!(!auth.authorize(&request("bob", valid)).await.unwrap());
+
+        let list = token(json!({"tunnels": ["a", "b"], "exp": now + 600}), b"secret");
+        assert!(auth.authorize(&request("b", list)).await.unwrap());
+
+        let expired = token(json!({"tunnels": "alice", "exp": now - 600}), b"secret");
+        assert!(!auth.authorize(&request("alice", expired)).await.unwrap());
+        let early = token(
+            json!({"tunnels": "alice", "exp": now + 1200, "nbf": now + 600}),
+            b"secret",
+        );
+        assert!(!auth.authorize(&request("alice", early)).await.unwrap());
+        let forged = token(json!({"tunnels": "alice", "exp": now + 600}), b"guess");
+        assert!(!auth.authorize(&request("alice", forged)).await.unwrap());
+        assert!(!auth
+            .authorize(&request("alice", "not a token".to_string()))
+            .await
+            .unwrap());
+    }
+}
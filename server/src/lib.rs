@@ -6,37 +6,78 @@
 extern crate lazy_static;
 
 use std::time::Duration;
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
 use actix_web::{web, App, HttpServer};
-use anyhow::Result;
-use dotenvy::dotenv;
-use hyper::{server::conn::http1, service::service_fn};
+use anyhow::{Context, Result};
 use tokio::{net::TcpListener, sync::Mutex, time::timeout};
 
-use crate::api::{api_status, api_tunnel_status, request_endpoint, request_root};
+use crate::access_log::AccessLog;
+use crate::admin::{
+    block_subdomain, get_tunnel, kick_tunnel, list_blocked, list_reservations, list_tunnels,
+    release_subdomain, reserve_subdomain, unblock_subdomain,
+};
+use crate::api::{
+    api_metrics, api_status, api_tunnel_status, request_endpoint, request_root, tunnel_password,
+};
+use crate::auth_limit::FailureLimiter;
 use crate::config::Config;
-use crate::proxy::proxy_handler;
-use crate::state::{ClientManager, State};
+use crate::proxy::{serve_proxy, ProxyContext};
+use crate::proxy_protocol::RelayedCallers;
+use crate::reservations::Reservations;
+use crate::state::{ClientManager, SocketWait, State};
 
+mod access_log;
+mod admin;
 mod api;
 mod auth;
+mod auth_cache;
+mod auth_limit;
 mod config;
 mod error;
+mod error_page;
+mod handshake;
+mod htpasswd;
+mod jwt;
+mod metrics;
+mod mux;
 mod proxy;
+mod proxy_protocol;
+mod reminder;
+mod reservations;
 mod state;
+mod stream;
+mod tls;
 mod types;
+mod webhook;
 
+pub use crate::access_log::{AccessLogConfig, AccessLogFormat};
+pub use crate::auth::{Auth, AuthDecision, AuthRequest, CfWorkerStore, NoAuth, PlaintextPassword};
+pub use crate::auth_cache::CachedAuth;
+pub use crate::error_page::ErrorPages;
+pub use crate::htpasswd::HtpasswdFile;
+pub use crate::jwt::{JwtAuth, JwtConfig, JwtKeySource};
+pub use crate::tls::TlsConfig;
 pub use crate::types::AuthMode;
+pub use crate::webhook::WebhookAuth;
+/// For implementing [`Auth`].
+pub use async_trait::async_trait;
+/// For [`JwtConfig::algorithm`].
+pub use jsonwebtoken::Algorithm as JwtAlgorithm;
 
 /// The interval between cleanup checks
 const CLEANUP_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// How long tunnel clients get to answer a request by default
+const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long visitors wait for a free tunnel connection by default
+const DEFAULT_SOCKET_WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_WAITING_REQUESTS: usize = 100;
+const DEFAULT_AUTH_MAX_FAILURES: u32 = 10;
+const DEFAULT_AUTH_FAILURE_WINDOW: Duration = Duration::from_secs(300);
 
 lazy_static! {
-    static ref CONFIG: Config = {
-        dotenv().ok();
-        envy::from_env::<Config>().unwrap_or_default()
-    };
+    /// Checked by [`start`], which refuses to run with a malformed variable.
+    static ref CONFIG: Config = Config::from_env().unwrap_or_default();
 }
 
 pub struct ServerConfig {
@@ -46,6 +87,70 @@ pub struct ServerConfig {
     pub max_sockets: u8,
     pub proxy_port: u16,
     pub auth_mode: AuthMode,
+    /// Authorize registrations with this backend instead of the one of `auth_mode`.
+    pub auth: Option<Arc<dyn Auth>>,
+    /// Failed registrations allowed per address within `auth_failure_window`,
+    /// any more get a 429 without asking the backend. Zero allows any.
+    pub auth_max_failures: u32,
+    pub auth_failure_window: Duration,
+    /// Accept tunnel sockets from clients which don't authenticate them, like the original localtunnel client.
+    pub allow_legacy_clients: bool,
+    /// Serve HTTPS and WSS on `proxy_port` with this certificate, implies `secure`.
+    pub tls: Option<TlsConfig>,
+    /// Show browsers a reminder page before they reach a tunnel, until they enter its password.
+    pub visitor_reminder: bool,
+    /// Pages shown to visitors when a request can't be proxied.
+    pub error_pages: ErrorPages,
+    /// Answer with 504 when a tunnel client takes longer than this to respond.
+    pub upstream_timeout: Duration,
+    /// How long a visitor waits for a free tunnel connection before getting a 503, zero fails at once.
+    pub socket_wait_timeout: Duration,
+    /// Visitors allowed to wait for a connection of one tunnel, any more get a 503 at once.
+    pub max_waiting_requests: usize,
+    /// Expect a PROXY protocol header, v1 or v2, on every connection to `proxy_port`,
+    /// as sent by a load balancer in front of the server.
+    pub proxy_protocol: bool,
+    /// Expect a PROXY protocol header on every connection to `api_port`, like `proxy_protocol`.
+    pub api_proxy_protocol: bool,
+    /// Keep the `X-Forwarded-*` and `Forwarded` headers of visitors, set by a reverse proxy in front of the server.
+    /// Otherwise they are replaced.
+    pub trust_forwarded_headers: bool,
+    /// Take the address of api callers from the `X-Forwarded-For` header, set by a reverse proxy
+    /// in front of the api. It is the tunnel password and what failed logins are counted by.
+    pub api_trust_forwarded_headers: bool,
+    /// Log every proxied request.
+    pub access_log: Option<AccessLogConfig>,
+    /// JSON file keeping reserved subdomains across restarts, managed through the admin api.
+    pub reservations: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            domain: "localhost".to_string(),
+            api_port: 3000,
+            secure: false,
+            max_sockets: 10,
+            proxy_port: 3001,
+            auth_mode: AuthMode::default(),
+            auth: None,
+            auth_max_failures: DEFAULT_AUTH_MAX_FAILURES,
+            auth_failure_window: DEFAULT_AUTH_FAILURE_WINDOW,
+            allow_legacy_clients: false,
+            tls: None,
+            visitor_reminder: false,
+            error_pages: ErrorPages::default(),
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            socket_wait_timeout: DEFAULT_SOCKET_WAIT_TIMEOUT,
+            max_waiting_requests: DEFAULT_MAX_WAITING_REQUESTS,
+            proxy_protocol: false,
+            api_proxy_protocol: false,
+            trust_forwarded_headers: false,
+            api_trust_forwarded_headers: false,
+            access_log: None,
+            reservations: None,
+        }
+    }
 }
 
 /// Start the proxy use low level api from hyper.
@@ -58,45 +163,117 @@ pub async fn start(config: ServerConfig) -> Result<()> {
         max_sockets,
         proxy_port,
         auth_mode,
+        auth,
+        auth_max_failures,
+        auth_failure_window,
+        allow_legacy_clients,
+        tls,
+        visitor_reminder,
+        error_pages,
+        upstream_timeout,
+        socket_wait_timeout,
+        max_waiting_requests,
+        proxy_protocol,
+        api_proxy_protocol,
+        trust_forwarded_headers,
+        api_trust_forwarded_headers,
+        access_log,
+        reservations,
     } = config;
+    // Otherwise a single malformed variable silently drops all of them, e.g. ADMIN_TOKEN
+    Config::from_env().context("Invalid environment variable")?;
+    let tls = tls.map(tls::acceptor).transpose()?;
+    let access_log = access_log.map(AccessLog::start).transpose()?;
+    let secure = secure || tls.is_some();
     log::info!("Api server listens at {} {}", &domain, api_port);
+    let auth = match auth {
+        Some(auth) => auth,
+        None => {
+            auth::validate(&auth_mode, &CONFIG)?;
+            auth::from_mode(&auth_mode).await?
+        }
+    };
     log::info!(
         "Start proxy server at {} {}, options: {} {}, auth mode: {}",
         &domain,
         proxy_port,
         secure,
         max_sockets,
-        auth_mode
+        auth.name()
     );
 
-    auth::validate(&auth_mode, &CONFIG)?;
+    if allow_legacy_clients {
+        log::warn!("Tunnel sockets of legacy clients are accepted without authentication");
+    }
 
-    let manager = Arc::new(Mutex::new(ClientManager::new(max_sockets)));
+    let reservations = match reservations {
+        Some(path) => Reservations::load(path)?,
+        None => Reservations::default(),
+    };
+    let manager = Arc::new(Mutex::new(ClientManager::new(
+        max_sockets,
+        allow_legacy_clients,
+        SocketWait {
+            timeout: socket_wait_timeout,
+            max_waiting: max_waiting_requests,
+        },
+        reservations,
+    )));
     let api_state = web::Data::new(State {
         manager: manager.clone(),
-        max_sockets,
-        auth_mode,
+        auth,
+        auth_failures: FailureLimiter::new(auth_max_failures, auth_failure_window),
+        trust_forwarded_headers: api_trust_forwarded_headers,
         secure,
         domain,
     });
 
+    let proxy_context = Arc::new(ProxyContext {
+        manager: manager.clone(),
+        visitor_reminder,
+        secure,
+        error_pages,
+        upstream_timeout,
+        trust_forwarded_headers,
+        access_log,
+    });
+
     let proxy_addr: SocketAddr = ([0, 0, 0, 0], proxy_port).into();
     let listener = TcpListener::bind(proxy_addr).await?;
     tokio::spawn(async move {
         loop {
             match timeout(CLEANUP_CHECK_INTERVAL, listener.accept()).await {
-                Ok(Ok((stream, _))) => {
+                Ok(Ok((mut stream, mut visitor))) => {
                     log::info!("Accepted a new proxy request");
 
-                    let proxy_manager = manager.clone();
-                    let service = service_fn(move |req| proxy_handler(req, proxy_manager.clone()));
-
+                    let proxy_context = proxy_context.clone();
+                    let tls = tls.clone();
                     tokio::spawn(async move {
-                        if let Err(err) = http1::Builder::new()
-                            .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
-                            .with_upgrades()
-                            .await
-                        {
+                        if proxy_protocol {
+                            match proxy_protocol::read_header(&mut stream).await {
+                                Ok(Some(address)) => visitor = address,
+                                Ok(None) => {}
+                                Err(err) => {
+                                    log::debug!(
+                                        "Invalid PROXY protocol header from {}: {:?}",
+                                        visitor,
+                                        err
+                                    );
+                                    return;
+                                }
+                            }
+                        }
+                        let res = match tls {
+                            Some(acceptor) => match acceptor.accept(stream).await {
+                                Ok(stream) => serve_proxy(stream, visitor, proxy_context).await,
+                                Err(err) => {
+                                    log::debug!("TLS handshake failed: {:?}", err);
+                                    return;
+                                }
+                            },
+                            None => serve_proxy(stream, visitor, proxy_context).await,
+                        };
+                        if let Err(err) = res {
                             log::error!("Failed to serve connection: {:?}", err);
                         }
                     });
@@ -111,17 +288,42 @@ pub async fn start(config: ServerConfig) -> Result<()> {
         }
     });
 
-    HttpServer::new(move || {
+    let api_server = HttpServer::new(move || {
         App::new()
             .app_data(api_state.clone())
             .service(api_status)
             .service(api_tunnel_status)
+            .service(tunnel_password)
+            .service(api_metrics)
+            .service(list_tunnels)
+            .service(get_tunnel)
+            .service(kick_tunnel)
+            .service(list_blocked)
+            .service(block_subdomain)
+            .service(unblock_subdomain)
+            .service(list_reservations)
+            .service(reserve_subdomain)
+            .service(release_subdomain)
             .service(request_endpoint)
             .service(request_root)
-    })
-    .bind(("0.0.0.0", api_port))?
-    .run()
-    .await?;
+    });
+    let api_server = if api_proxy_protocol {
+        // Actix can't strip the header, so a relay does and passes the connections on over loopback
+        let internal = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+        let public = TcpListener::bind(("0.0.0.0", api_port)).await?;
+        let callers = RelayedCallers::default();
+        tokio::spawn(proxy_protocol::relay(
+            public,
+            internal.local_addr()?,
+            callers.clone(),
+        ));
+        api_server
+            .on_connect(move |conn, data| callers.recover(conn, data))
+            .listen(internal)?
+    } else {
+        api_server.bind(("0.0.0.0", api_port))?
+    };
+    api_server.run().await?;
 
     Ok(())
 }
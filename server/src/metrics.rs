@@ -0,0 +1,314 @@
+//! Server metrics, rendered in the Prometheus text format at `/metrics`.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use hyper::StatusCode;
+
+use crate::error::ServerError;
+use crate::state::ClientManager;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Outcome of checking the credential of a registration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AuthOutcome {
+    Success,
+    Failure,
+    Error,
+    /// Turned away after too many failures, without asking the backend.
+    RateLimited,
+}
+
+impl AuthOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AuthOutcome::Success => "success",
+            AuthOutcome::Failure => "failure",
+            AuthOutcome::Error => "error",
+            AuthOutcome::RateLimited => "rate_limited",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Proxied responses by status class, 1xx to 5xx.
+    responses: [AtomicU64; 5],
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    upgrades: AtomicU64,
+    active_upgrades: AtomicI64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    auth: Mutex<BTreeMap<(String, AuthOutcome), u64>>,
+}
+
+impl Metrics {
+    pub fn record_response(&self, status: StatusCode) {
+        let class = (status.as_u16() / 100).clamp(1, 5) as usize;
+        self.responses[class - 1].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an error by its [`ServerError`] variant, anything else as `Other`.
+    pub fn record_error(&self, err: &anyhow::Error) {
+        let kind = err
+            .downcast_ref::<ServerError>()
+            .map(ServerError::kind)
+            .unwrap_or("Other");
+        self.record_server_error(kind);
+    }
+
+    pub fn record_server_error(&self, kind: &'static str) {
+        *self
+            .errors
+            .lock()
+            .expect("Metrics lock poisoned")
+            .entry(kind)
+            .or_default() += 1;
+    }
+
+    /// Count an upgraded connection, which stays active until the returned guard is dropped.
+    pub fn upgrade_started(&self) -> UpgradeGuard<'_> {
+        self.upgrades.fetch_add(1, Ordering::Relaxed);
+        self.active_upgrades.fetch_add(1, Ordering::Relaxed);
+        UpgradeGuard { metrics: self }
+    }
+
+    pub fn record_traffic(&self, sent: u64, received: u64) {
+        self.bytes_sent.fetch_add(sent, Ordering::Relaxed);
+        self.bytes_received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    /// Count a credential check by the name of the auth backend.
+    pub fn record_auth(&self, mode: &str, outcome: AuthOutcome) {
+        *self
+            .auth
+            .lock()
+            .expect("Metrics lock poisoned")
+            .entry((mode.to_string(), outcome))
+            .or_default() += 1;
+    }
+
+    /// Render all metrics, with the gauges of every tunnel in `manager` if `per_tunnel`.
+    /// They are labelled with the subdomains, which aren't for everyone to see.
+    pub async fn render(&self, manager: &ClientManager, per_tunnel: bool) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "lt_tunnels", "gauge", "Registered tunnels.");
+        sample(&mut out, "lt_tunnels", &[], manager.clients.len() as u64);
+
+        if per_tunnel {
+            render_tunnels(&mut out, manager).await;
+        }
+
+        header(
+            &mut out,
+            "lt_proxy_responses_total",
+            "counter",
+            "Proxied responses by status class.",
+        );
+        for (i, count) in self.responses.iter().enumerate() {
+            let class = format!("{}xx", i + 1);
+            sample(
+                &mut out,
+                "lt_proxy_responses_total",
+                &[("class", &class)],
+                count.load(Ordering::Relaxed),
+            );
+        }
+
+        header(
+            &mut out,
+            "lt_errors_total",
+            "counter",
+            "Server errors by kind.",
+        );
+        for (kind, count) in self.errors.lock().expect("Metrics lock poisoned").iter() {
+            sample(&mut out, "lt_errors_total", &[("kind", kind)], *count);
+        }
+
+        header(
+            &mut out,
+            "lt_upgrades_total",
+            "counter",
+            "Upgraded connections, e.g. WebSockets.",
+        );
+        sample(
+            &mut out,
+            "lt_upgrades_total",
+            &[],
+            self.upgrades.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "lt_active_upgrades",
+            "gauge",
+            "Upgraded connections still open.",
+        );
+        sample(
+            &mut out,
+            "lt_active_upgrades",
+            &[],
+            self.active_upgrades.load(Ordering::Relaxed).max(0) as u64,
+        );
+
+        header(
+            &mut out,
+            "lt_bytes_sent_total",
+            "counter",
+            "Bytes sent to tunnel clients.",
+        );
+        sample(
+            &mut out,
+            "lt_bytes_sent_total",
+            &[],
+            self.bytes_sent.load(Ordering::Relaxed),
+        );
+        header(
+            &mut out,
+            "lt_bytes_received_total",
+            "counter",
+            "Bytes received from tunnel clients.",
+        );
+        sample(
+            &mut out,
+            "lt_bytes_received_total",
+            &[],
+            self.bytes_received.load(Ordering::Relaxed),
+        );
+
+        header(
+            &mut out,
+            "lt_auth_total",
+            "counter",
+            "Credential checks by auth mode and outcome.",
+        );
+        for ((mode, outcome), count) in self.auth.lock().expect("Metrics lock poisoned").iter() {
+            sample(
+                &mut out,
+                "lt_auth_total",
+                &[("mode", mode), ("outcome", outcome.as_str())],
+                *count,
+            );
+        }
+
+        if let Some(usage) = memory_stats::memory_stats() {
+            header(
+                &mut out,
+                "process_resident_memory_bytes",
+                "gauge",
+                "Resident memory size in bytes.",
+            );
+            sample(
+                &mut out,
+                "process_resident_memory_bytes",
+                &[],
+                usage.physical_mem as u64,
+            );
+        }
+
+        out
+    }
+}
+
+pub struct UpgradeGuard<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for UpgradeGuard<'_> {
+    fn drop(&mut self) {
+        self.metrics.active_upgrades.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Gauges of every tunnel, labelled with its subdomain.
+async fn render_tunnels(out: &mut String, manager: &ClientManager) {
+    let mut tunnels = Vec::with_capacity(manager.clients.len());
+    for (id, client) in manager.clients.iter() {
+        let client = client.lock().await;
+        tunnels.push((id.clone(), client.stats().await));
+    }
+    tunnels.sort_by(|a, b| a.0.cmp(&b.0));
+    header(
+        out,
+        "lt_tunnel_sockets",
+        "gauge",
+        "Pooled sockets of a tunnel.",
+    );
+    for (id, stats) in tunnels.iter() {
+        sample(
+            out,
+            "lt_tunnel_sockets",
+            &[("tunnel", id)],
+            stats.connected_sockets as u64,
+        );
+    }
+    header(
+        out,
+        "lt_tunnel_multiplexed",
+        "gauge",
+        "Whether a tunnel has a multiplexed session.",
+    );
+    for (id, stats) in tunnels.iter() {
+        sample(
+            out,
+            "lt_tunnel_multiplexed",
+            &[("tunnel", id)],
+            stats.multiplexed as u64,
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: u64) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        let labels = labels
+            .iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = write!(out, "{{{}}}", labels);
+    }
+    let _ = writeln!(out, " {}", value);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sample;
+
+    #[test]
+    fn sample_works() {
+        let mut out = String::new();
+        sample(&mut out, "lt_tunnels", &[], 3);
+        sample(
+            &mut out,
+            "lt_errors_total",
+            &[("kind", "a\"b\\c\nd"), ("x", "y")],
+            1,
+        );
+        assert_eq!(
+            out,
+            "lt_tunnels 3\nlt_errors_total{kind=\"a\\\"b\\\\c\\nd\",x=\"y\"} 1\n"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use std::{future::poll_fn, io, task::Poll};
+
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
+
+/// Name of the multiplexed transport, as negotiated through the proxy info.
+pub const MUX_TRANSPORT: &str = "yamux";
+/// Transport of clients which open a socket per connection.
+pub const SOCKETS_TRANSPORT: &str = "sockets";
+
+type StreamRequest = oneshot::Sender<io::Result<yamux::Stream>>;
+
+/// Handle to a multiplexed session with a client, opening a stream per proxied connection.
+#[derive(Clone)]
+pub struct MuxSession {
+    requests: mpsc::Sender<StreamRequest>,
+}
+
+impl MuxSession {
+    /// Start a session on an authenticated socket.
+    /// The returned driver has to be polled for the session to make progress,
+    /// and resolves once the client disconnects or every handle is dropped.
+    pub fn new(socket: TcpStream) -> (Self, impl std::future::Future<Output = ()>) {
+        let (requests, receiver) = mpsc::channel(1);
+        let connection = yamux::Connection::new(
+            socket.compat(),
+            yamux::Config::default(),
+            yamux::Mode::Client,
+        );
+        (MuxSession { requests }, drive(connection, receiver))
+    }
+
+    /// Open a new stream to the client.
+    pub async fn open(&self) -> io::Result<Compat<yamux::Stream>> {
+        let (tx, rx) = oneshot::channel();
+        let closed = || io::Error::new(io::ErrorKind::NotConnected, "multiplexed session closed");
+        self.requests.send(tx).await.map_err(|_| closed())?;
+        let stream = rx.await.map_err(|_| closed())??;
+        Ok(stream.compat())
+    }
+
+    pub fn same_session(&self, other: &MuxSession) -> bool {
+        self.requests.same_channel(&other.requests)
+    }
+}
+
+/// Poll the connection, which also moves the data of all its streams,
+/// and open the outbound streams requested through the session handles.
+async fn drive(
+    mut connection: yamux::Connection<Compat<TcpStream>>,
+    mut requests: mpsc::Receiver<StreamRequest>,
+) {
+    let mut pending: Option<StreamRequest> = None;
+    let res = poll_fn(|cx| loop {
+        match connection.poll_next_inbound(cx) {
+            Poll::Ready(Some(Ok(stream))) => {
+                log::debug!("Drop stream {} opened by the client", stream.id());
+                continue;
+            }
+            Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(err)),
+            Poll::Ready(None) => return Poll::Ready(Ok(())),
+            Poll::Pending => {}
+        }
+
+        if pending.is_none() {
+            match requests.poll_recv(cx) {
+                Poll::Ready(Some(request)) => pending = Some(request),
+                // Every handle is gone, the client was replaced or cleaned up
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => {}
+            }
+        }
+
+        if let Some(request) = pending.take() {
+            match connection.poll_new_outbound(cx) {
+                Poll::Ready(Ok(stream)) => {
+                    let _ = request.send(Ok(stream));
+                    continue;
+                }
+                Poll::Ready(Err(err)) => {
+                    let _ = request.send(Err(io::Error::other(err.to_string())));
+                    return Poll::Ready(Err(err));
+                }
+                Poll::Pending => pending = Some(request),
+            }
+        }
+
+        return Poll::Pending;
+    })
+    .await;
+
+    if let Err(err) = res {
+        log::info!("Multiplexed session failed: {}", err);
+    }
+    if let Err(err) = poll_fn(|cx| connection.poll_close(cx)).await {
+        log::debug!("Failed to close multiplexed session: {}", err);
+    }
+}
@@ -1,35 +1,125 @@
-use std::sync::Arc;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::Result;
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{
-    body::Incoming,
-    header::{HOST, UPGRADE},
+    body::{Bytes, Incoming},
+    header::{HeaderMap, HeaderName, HeaderValue, FORWARDED, HOST, REFERER, UPGRADE, USER_AGENT},
+    server::conn::http1,
+    service::service_fn,
     upgrade::OnUpgrade,
     Request, Response, StatusCode,
 };
 use regex::Regex;
-use tokio::sync::Mutex;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::Mutex,
+};
 
+use crate::access_log::{AccessLog, Entry};
 use crate::error::ServerError;
-use crate::state::ClientManager;
+use crate::error_page::ErrorPages;
+use crate::metrics::METRICS;
+use crate::state::{Client, ClientManager};
+use crate::types::TunnelType;
+
+/// Body of the responses to visitors, relayed from the tunnel or made up by the server.
+pub type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// What the proxy needs to serve visitors.
+pub struct ProxyContext {
+    pub manager: Arc<Mutex<ClientManager>>,
+    /// Show browsers a reminder page before they reach a tunnel.
+    pub visitor_reminder: bool,
+    pub secure: bool,
+    /// Pages served instead of a proxied response when the tunnel can't be reached.
+    pub error_pages: ErrorPages,
+    /// How long the tunnel client may take to answer with response headers.
+    pub upstream_timeout: Duration,
+    /// Extend the forwarded headers of requests instead of replacing them,
+    /// for servers behind a reverse proxy which sets them.
+    pub trust_forwarded_headers: bool,
+    pub access_log: Option<AccessLog>,
+}
+
+/// The tunnel connection a response came through, kept in its extensions for the access log.
+#[derive(Debug, Clone)]
+struct TunnelConnection(String);
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
 
 lazy_static! {
     static ref PROTOCOL_REGEX: Regex = Regex::new(r"(https?|wss?)://").expect("Invalid Regex");
 }
 
+/// Serve the requests of one visitor connection, plain or TLS.
+/// `visitor` is the address of the visitor, as told by the PROXY protocol if it is enabled.
+pub async fn serve_proxy<S>(
+    stream: S,
+    visitor: SocketAddr,
+    context: Arc<ProxyContext>,
+) -> hyper::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let context = context.clone();
+        async move {
+            let started = Instant::now();
+            let headers = req.headers().clone();
+            let entry = context
+                .access_log
+                .as_ref()
+                .map(|_| access_log_entry(&req, visitor, context.trust_forwarded_headers));
+            let mut response = match proxy_handler(req, visitor, context.clone()).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::warn!("Failed to proxy request: {:?}", err);
+                    METRICS.record_error(&err);
+                    context.error_pages.respond(&err, &headers)
+                }
+            };
+            METRICS.record_response(response.status());
+
+            if let (Some(access_log), Some(mut entry)) = (&context.access_log, entry) {
+                entry.status = response.status().as_u16();
+                entry.upgrade = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+                entry.connection = response
+                    .extensions_mut()
+                    .remove::<TunnelConnection>()
+                    .map(|connection| connection.0);
+                response = response.map(|body| access_log.wrap(body, entry, started).boxed());
+            }
+            Ok::<_, Infallible>(response)
+        }
+    });
+    http1::Builder::new()
+        .serve_connection(hyper_util::rt::TokioIo::new(stream), service)
+        .with_upgrades()
+        .await
+}
+
 /// Reverse proxy handler
 pub async fn proxy_handler(
     mut req: Request<Incoming>,
-    manager: Arc<Mutex<ClientManager>>,
-) -> Result<Response<Incoming>> {
+    visitor: SocketAddr,
+    context: Arc<ProxyContext>,
+) -> Result<Response<ProxyBody>> {
     let host_header = req.headers().get(HOST).ok_or(ServerError::NoHostHeader)?;
-    let hostname = host_header.to_str()?;
+    let hostname = host_header.to_str()?.to_string();
     log::debug!("Request hostname: {}", hostname);
 
-    let endpoint = extract(hostname)?;
+    let endpoint = extract(&hostname)?;
 
     let client = {
-        let manager = manager.lock().await;
+        let manager = context.manager.lock().await;
         manager
             .clients
             .get(&endpoint)
@@ -37,10 +127,30 @@ pub async fn proxy_handler(
             .ok_or(ServerError::ProxyNotReady)?
     };
 
-    let client_stream = {
-        let mut client = client.lock().await;
-        client.take().await.ok_or(ServerError::EmptyConnection)?
+    let gate = {
+        let client = client.lock().await;
+        if client.tunnel_type != TunnelType::Http {
+            return Err(ServerError::NotHttpTunnel.into());
+        }
+        client.visitor_gate.clone()
     };
+    if let Some(gate) = gate.filter(|_| context.visitor_reminder) {
+        if !gate.lets_through(&req) {
+            return Ok(gate.respond(req, context.secure).await);
+        }
+    }
+
+    let proto = if context.secure { "https" } else { "http" };
+    add_forwarded_headers(
+        req.headers_mut(),
+        visitor,
+        proto,
+        &hostname,
+        context.trust_forwarded_headers,
+    );
+
+    let client_stream = Client::connect(&client).await?;
+    let connection = TunnelConnection(client_stream.describe());
     let client_stream = hyper_util::rt::TokioIo::new(client_stream);
 
     if !req.headers().contains_key(UPGRADE) {
@@ -51,8 +161,8 @@ pub async fn proxy_handler(
             }
         });
 
-        let response = sender.send_request(req).await?;
-        Ok(response)
+        let response = send_request(&mut sender, req, context.upstream_timeout).await?;
+        Ok(relayed(response, connection))
     } else {
         let (mut sender, conn) = hyper::client::conn::http1::handshake(client_stream).await?;
         let conn = conn.with_upgrades();
@@ -73,7 +183,7 @@ pub async fn proxy_handler(
             .remove::<OnUpgrade>()
             .ok_or(ServerError::NoUpgradeExtension)?;
 
-        let mut response = sender.send_request(req).await?;
+        let mut response = send_request(&mut sender, req, context.upstream_timeout).await?;
 
         if response.status() == StatusCode::SWITCHING_PROTOCOLS {
             let response_upgrade_type = response
@@ -92,6 +202,7 @@ pub async fn proxy_handler(
                 log::info!("Responding to a connection upgrade response");
 
                 tokio::spawn(async move {
+                    let _upgrade = METRICS.upgrade_started();
                     match request_upgraded.await {
                         Ok(request_upgraded) => {
                             let mut response_upgraded =
@@ -114,11 +225,134 @@ pub async fn proxy_handler(
                     }
                 });
             }
-            Ok(response)
+            Ok(relayed(response, connection))
         } else {
-            Ok(response)
+            Ok(relayed(response, connection))
+        }
+    }
+}
+
+fn relayed(response: Response<Incoming>, connection: TunnelConnection) -> Response<ProxyBody> {
+    let mut response = response.map(BodyExt::boxed);
+    response.extensions_mut().insert(connection);
+    response
+}
+
+/// What the access log records about a request, before it is proxied.
+///
+/// With `trust_forwarded_headers` the visitor is the last address a reverse proxy in front added.
+fn access_log_entry(
+    req: &Request<Incoming>,
+    visitor: SocketAddr,
+    trust_forwarded_headers: bool,
+) -> Entry {
+    let header = |name: HeaderName| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let forwarded_ip = header(X_FORWARDED_FOR)
+        .filter(|_| trust_forwarded_headers)
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok());
+    let subdomain = header(HOST)
+        .and_then(|host| extract(host).ok())
+        .unwrap_or_default();
+
+    Entry {
+        time: SystemTime::now(),
+        subdomain,
+        client_ip: forwarded_ip.unwrap_or(visitor.ip()).to_canonical(),
+        method: req.method().to_string(),
+        path: req
+            .uri()
+            .path_and_query()
+            .map(|path| path.to_string())
+            .unwrap_or_default(),
+        protocol: format!("{:?}", req.version()),
+        status: 0,
+        bytes: 0,
+        latency_ms: 0,
+        upgrade: false,
+        connection: None,
+        referer: header(REFERER).map(str::to_string),
+        user_agent: header(USER_AGENT).map(str::to_string),
+    }
+}
+
+/// Send the request through the tunnel, giving up if the response headers take too long.
+async fn send_request(
+    sender: &mut hyper::client::conn::http1::SendRequest<Incoming>,
+    req: Request<Incoming>,
+    upstream_timeout: Duration,
+) -> Result<Response<Incoming>> {
+    let response = tokio::time::timeout(upstream_timeout, sender.send_request(req))
+        .await
+        .map_err(|_| ServerError::UpstreamTimeout)??;
+    Ok(response)
+}
+
+/// Tell the local service who the visitor is, and how the request reached the server.
+///
+/// Unless `trusted`, the headers sent by visitors are dropped first, so they can't be spoofed.
+fn add_forwarded_headers(
+    headers: &mut HeaderMap,
+    visitor: SocketAddr,
+    proto: &str,
+    host: &str,
+    trusted: bool,
+) {
+    if !trusted {
+        for name in [
+            FORWARDED,
+            X_FORWARDED_FOR,
+            X_FORWARDED_PROTO,
+            X_FORWARDED_HOST,
+        ] {
+            headers.remove(name);
         }
     }
+
+    let ip = visitor.ip().to_canonical();
+    let forwarded_for = match headers.get(&X_FORWARDED_FOR).and_then(|v| v.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, ip),
+        None => ip.to_string(),
+    };
+    // IPv6 addresses are quoted and bracketed in Forwarded, see RFC 7239
+    let node = match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    };
+    let element = format!(
+        "for={};proto={};host=\"{}\"",
+        node,
+        proto,
+        host.replace('"', "")
+    );
+    let forwarded = match headers.get(&FORWARDED).and_then(|v| v.to_str().ok()) {
+        Some(previous) => format!("{}, {}", previous, element),
+        None => element,
+    };
+
+    let mut set = |name: HeaderName, value: String, overwrite: bool| {
+        if !overwrite && headers.contains_key(&name) {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    };
+    set(X_FORWARDED_FOR, forwarded_for, true);
+    set(FORWARDED, forwarded, true);
+    // A trusted proxy in front knows better what the visitor used
+    set(X_FORWARDED_PROTO, proto.to_string(), false);
+    set(X_FORWARDED_HOST, host.to_string(), false);
+}
+
+pub fn full(body: impl Into<Bytes>) -> ProxyBody {
+    Full::new(body.into())
+        .map_err(|never| match never {})
+        .boxed()
 }
 
 fn extract(hostname: &str) -> Result<String> {
@@ -134,7 +368,9 @@ fn extract(hostname: &str) -> Result<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::extract;
+    use hyper::header::{HeaderMap, HeaderValue, FORWARDED};
+
+    use super::{add_forwarded_headers, extract, X_FORWARDED_FOR, X_FORWARDED_HOST};
 
     #[test]
     fn extract_subdomain_works() {
@@ -153,4 +389,37 @@ mod tests {
             assert_eq!(extract(domain).unwrap(), subdomain);
         }
     }
+
+    #[test]
+    fn add_forwarded_headers_works() {
+        let mut headers = HeaderMap::new();
+        headers.insert(&X_FORWARDED_FOR, HeaderValue::from_static("6.6.6.6"));
+        add_forwarded_headers(
+            &mut headers,
+            "[::ffff:192.0.2.1]:1234".parse().unwrap(),
+            "https",
+            "demo.example.org",
+            false,
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "192.0.2.1");
+        assert_eq!(headers[&X_FORWARDED_HOST], "demo.example.org");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=192.0.2.1;proto=https;host=\"demo.example.org\""
+        );
+
+        add_forwarded_headers(
+            &mut headers,
+            "[2001:db8::1]:1234".parse().unwrap(),
+            "http",
+            "other.example.org",
+            true,
+        );
+        assert_eq!(headers[&X_FORWARDED_FOR], "192.0.2.1, 2001:db8::1");
+        assert_eq!(headers[&X_FORWARDED_HOST], "demo.example.org");
+        assert_eq!(
+            headers[FORWARDED],
+            "for=192.0.2.1;proto=https;host=\"demo.example.org\", for=\"[2001:db8::1]\";proto=http;host=\"other.example.org\""
+        );
+    }
 }
@@ -0,0 +1,256 @@
+//! HAProxy PROXY protocol, v1 and v2, as sent by load balancers in front of the server.
+//! See https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::{
+    any::Any,
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use actix_web::dev::Extensions;
+use anyhow::{anyhow, bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    time::timeout,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// How long a load balancer may take to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read the PROXY protocol header which starts the connection.
+///
+/// Returns the address of the original client, or `None` when the header doesn't carry one,
+/// e.g. for the health checks of the load balancer. Nothing past the header is consumed.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    timeout(HEADER_TIMEOUT, read(stream))
+        .await
+        .map_err(|_| anyhow!("Timed out reading the PROXY protocol header"))?
+}
+
+async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<SocketAddr>> {
+    // Both versions are at least this long, so it never reads past a header
+    let mut start = [0u8; 8];
+    stream.read_exact(&mut start).await?;
+
+    if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                bail!("PROXY protocol v1 header is too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else if start == V2_SIGNATURE[..8] {
+        let mut header = [0u8; 16];
+        header[..8].copy_from_slice(&start);
+        stream.read_exact(&mut header[8..]).await?;
+        let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+        let mut addresses = vec![0u8; len];
+        stream.read_exact(&mut addresses).await?;
+        parse_v2(&header, &addresses)
+    } else {
+        bail!("Connection doesn't start with a PROXY protocol header")
+    }
+}
+
+/// Address of an api caller recovered from the PROXY protocol, in the connection data of its requests.
+#[derive(Debug, Clone, Copy)]
+pub struct CallerAddr(pub SocketAddr);
+
+/// Original addresses of the connections relayed to the api, by the local address of the relay socket.
+#[derive(Debug, Clone, Default)]
+pub struct RelayedCallers(Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>);
+
+impl RelayedCallers {
+    /// Attach the original address to a connection accepted by the api, see `HttpServer::on_connect`.
+    pub fn recover(&self, conn: &dyn Any, data: &mut Extensions) {
+        let Some(peer) = conn
+            .downcast_ref::<actix_web::rt::net::TcpStream>()
+            .and_then(|stream| stream.peer_addr().ok())
+        else {
+            return;
+        };
+        if let Some(caller) = self.0.lock().expect("Callers lock poisoned").remove(&peer) {
+            data.insert(CallerAddr(caller));
+        }
+    }
+}
+
+/// Strip the PROXY protocol header of each connection to `listener` and relay it to the api at `target`.
+///
+/// The api can't read the header itself, so it learns the original address through `callers`.
+pub async fn relay(listener: TcpListener, target: SocketAddr, callers: RelayedCallers) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::info!("Couldn't accept api connection: {:?}", err);
+                continue;
+            }
+        };
+        let callers = callers.clone();
+        tokio::spawn(async move {
+            let caller = match read_header(&mut stream).await {
+                Ok(caller) => caller,
+                Err(err) => {
+                    log::debug!("Invalid PROXY protocol header from {}: {:?}", peer, err);
+                    return;
+                }
+            };
+            let mut upstream = match connect(target, caller, &callers).await {
+                Ok(upstream) => upstream,
+                Err(err) => {
+                    log::error!("Failed to relay api connection: {:?}", err);
+                    return;
+                }
+            };
+            let local = upstream.local_addr().ok();
+            if let Err(err) = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await {
+                log::debug!("Api connection from {} failed: {:?}", peer, err);
+            }
+            // In case the api never accepted the connection
+            if let Some(local) = local {
+                callers
+                    .0
+                    .lock()
+                    .expect("Callers lock poisoned")
+                    .remove(&local);
+            }
+        });
+    }
+}
+
+/// Connect to the api, registering `caller` before the api can accept the connection.
+async fn connect(
+    target: SocketAddr,
+    caller: Option<SocketAddr>,
+    callers: &RelayedCallers,
+) -> std::io::Result<TcpStream> {
+    let socket = match target {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.bind(SocketAddr::new(target.ip(), 0))?;
+    let local = socket.local_addr()?;
+    if let Some(caller) = caller {
+        callers
+            .0
+            .lock()
+            .expect("Callers lock poisoned")
+            .insert(local, caller);
+    }
+    socket.connect(target).await
+}
+
+/// Parse a v1 header line, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?
+        .strip_suffix("\r\n")
+        .ok_or_else(|| anyhow!("PROXY protocol v1 header doesn't end with CRLF"))?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("Unsupported PROXY protocol v1 header: {}", line),
+    }
+    let (Some(source), Some(_destination), Some(source_port), Some(_destination_port), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        bail!("Malformed PROXY protocol v1 header: {}", line);
+    };
+    let ip: IpAddr = source.parse()?;
+    let port: u16 = source_port.parse()?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Parse a v2 header, the 16 fixed bytes and the address block following them.
+fn parse_v2(header: &[u8; 16], addresses: &[u8]) -> Result<Option<SocketAddr>> {
+    if &header[..12] != V2_SIGNATURE {
+        bail!("Invalid PROXY protocol v2 signature");
+    }
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    if version != 2 {
+        bail!("Unsupported PROXY protocol version {}", version);
+    }
+    match command {
+        // LOCAL, sent by the load balancer itself
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        _ => bail!("Unsupported PROXY protocol v2 command {}", command),
+    }
+
+    // Only the address family matters, TCP and UDP look the same
+    let address = match header[13] >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(ip.into(), port))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        0x1 | 0x2 => bail!("PROXY protocol v2 address block is too short"),
+        // UNSPEC or unix sockets
+        _ => None,
+    };
+    Ok(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::read_header;
+
+    #[tokio::test]
+    async fn read_header_works() {
+        let mut v1 = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n"[..];
+        let address = read_header(&mut v1).await.unwrap();
+        assert_eq!(
+            address,
+            Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(v1, b"GET / HTTP/1.1\r\n");
+
+        let mut v1_unknown = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_header(&mut v1_unknown).await.unwrap(), None);
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend_from_slice(&[0; 16]);
+        v2.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend_from_slice(b"rest");
+        let mut v2 = &v2[..];
+        let address = read_header(&mut v2).await.unwrap();
+        assert_eq!(
+            address,
+            Some("[2001:db8::1]:56324".parse::<SocketAddr>().unwrap())
+        );
+        assert_eq!(v2, b"rest");
+
+        let mut plain = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_header(&mut plain).await.is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    body::Incoming,
+    header::{CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE, USER_AGENT},
+    Method, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+
+use crate::handshake::constant_time_eq;
+use crate::proxy::{full, ProxyBody};
+
+/// Visitors sending this header, with any value, are never shown the reminder.
+const BYPASS_HEADER: &str = "bypass-tunnel-reminder";
+/// Remembers a visitor who entered the tunnel password.
+const VISITOR_COOKIE: &str = "lt_visitor";
+const VISITOR_COOKIE_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Where the reminder form is posted, taken by the server on every HTTP tunnel.
+const UNLOCK_PATH: &str = "/__localtunnel/unlock";
+const MAX_FORM_LEN: usize = 4096;
+const PAGE: &str = include_str!("reminder.html");
+
+/// What a browser needs to get past the reminder of one tunnel.
+#[derive(Debug)]
+pub struct VisitorGate {
+    /// The public IP address of the tunnel client, as served at `/mytunnelpassword`.
+    pub password: String,
+    /// Cookie value given out for the right password.
+    pub visitor_token: String,
+}
+
+#[derive(Deserialize)]
+struct UnlockForm {
+    password: String,
+    return_to: String,
+}
+
+impl VisitorGate {
+    /// Whether the request may go through the tunnel without seeing the reminder.
+    /// Only browsers are stopped, so API clients and tools keep working.
+    pub fn lets_through(&self, req: &Request<Incoming>) -> bool {
+        let headers = req.headers();
+        if headers.contains_key(BYPASS_HEADER) {
+            return true;
+        }
+        let is_browser = headers
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .is_some_and(|agent| agent.contains("Mozilla"));
+        if !is_browser {
+            return true;
+        }
+
+        headers
+            .get_all(COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .any(|(name, value)| {
+                name == VISITOR_COOKIE
+                    && constant_time_eq(value.as_bytes(), self.visitor_token.as_bytes())
+            })
+    }
+
+    /// Show the reminder page, or check the password it was submitted with.
+    pub async fn respond(&self, req: Request<Incoming>, secure: bool) -> Response<ProxyBody> {
+        if req.method() != Method::POST || req.uri().path() != UNLOCK_PATH {
+            let return_to = req
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/");
+            return page(StatusCode::UNAUTHORIZED, return_to, None);
+        }
+
+        let form = match Limited::new(req.into_body(), MAX_FORM_LEN).collect().await {
+            Ok(body) => serde_urlencoded::from_bytes::<UnlockForm>(&body.to_bytes()).ok(),
+            Err(err) => {
+                log::debug!("Failed to read the reminder form: {:?}", err);
+                None
+            }
+        };
+        let Some(form) = form else {
+            return page(
+                StatusCode::BAD_REQUEST,
+                "/",
+                Some("Invalid form submission."),
+            );
+        };
+        let return_to = local_path(&form.return_to);
+        if !constant_time_eq(form.password.trim().as_bytes(), self.password.as_bytes()) {
+            return page(
+                StatusCode::UNAUTHORIZED,
+                return_to,
+                Some("Wrong tunnel password."),
+            );
+        }
+
+        let mut cookie = format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+            VISITOR_COOKIE,
+            self.visitor_token,
+            VISITOR_COOKIE_MAX_AGE.as_secs()
+        );
+        if secure {
+            cookie.push_str("; Secure");
+        }
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(LOCATION, return_to)
+            .header(SET_COOKIE, cookie)
+            .body(full(""))
+            .expect("Valid reminder response")
+    }
+}
+
+fn page(status: StatusCode, return_to: &str, error: Option<&str>) -> Response<ProxyBody> {
+    let error = error
+        .map(|error| format!("<p class=\"error\">{}</p>", escape_html(error)))
+        .unwrap_or_default();
+    let body = PAGE
+        .replace("{{error}}", &error)
+        .replace("{{unlock_path}}", UNLOCK_PATH)
+        .replace("{{return_to}}", &escape_html(return_to));
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full(body))
+        .expect("Valid reminder response")
+}
+
+/// `path` if it stays within the tunnel and is fit for a `Location` header, otherwise `/`.
+/// Browsers take `\` for `/`, so `/\evil.com` would leave the tunnel like `//evil.com`.
+fn local_path(path: &str) -> &str {
+    let local = path.starts_with('/')
+        && !path.starts_with("//")
+        && !path.contains('\\')
+        && path.chars().all(|c| c.is_ascii_graphic());
+    if local {
+        path
+    } else {
+        "/"
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape_html, local_path};
+
+    #[test]
+    fn escape_html_works() {
+        assert_eq!(
+            escape_html(r#"/a?b=<script>"x"&'y'"#),
+            "/a?b=&lt;script&gt;&quot;x&quot;&amp;&#39;y&#39;"
+        );
+    }
+
+    #[test]
+    fn local_path_works() {
+        assert_eq!(local_path("/docs?page=2"), "/docs?page=2");
+        assert_eq!(local_path("/"), "/");
+        assert_eq!(local_path("//evil.com"), "/");
+        assert_eq!(local_path("/\\evil.com"), "/");
+        assert_eq!(local_path("/a\\b"), "/");
+        assert_eq!(local_path("/\n"), "/");
+        assert_eq!(local_path("/a b"), "/");
+        assert_eq!(local_path("https://evil.com"), "/");
+        assert_eq!(local_path(""), "/");
+    }
+}
@@ -0,0 +1,194 @@
+//! Subdomains reserved for the holder of an owner token, kept in a JSON file across restarts.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::handshake::constant_time_eq;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reservation {
+    /// Presented as the reclaim token by the client registering the subdomain.
+    pub token: String,
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct Reservations {
+    /// Where reservations are saved on every change, they only live in memory without it.
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, Reservation>,
+}
+
+impl Reservations {
+    /// Load the reservations saved at `path`, none if the file doesn't exist yet.
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let entries = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .with_context(|| format!("Invalid reservations file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("Failed to read {}", path.display()))
+            }
+        };
+        #[cfg(unix)]
+        if !entries.is_empty() {
+            // Written by a version which left it readable for anyone
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Failed to restrict {}", path.display()))?;
+        }
+        log::info!("Loaded {} subdomain reservations", entries.len());
+        Ok(Reservations {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    pub fn get(&self, subdomain: &str) -> Option<&Reservation> {
+        self.entries.get(subdomain)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Reservation)> {
+        self.entries.iter()
+    }
+
+    /// Whether `token` proves ownership of a reserved subdomain.
+    pub fn is_owner(&self, subdomain: &str, token: Option<&str>) -> bool {
+        match (self.entries.get(subdomain), token) {
+            (Some(reservation), Some(token)) => {
+                constant_time_eq(token.as_bytes(), reservation.token.as_bytes())
+            }
+            _ => false,
+        }
+    }
+
+    /// Reserve `subdomain` for `token`, replacing any previous reservation.
+    pub fn reserve(&mut self, subdomain: &str, token: String) -> Result<Reservation> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        let reservation = Reservation { token, created_at };
+        let previous = self
+            .entries
+            .insert(subdomain.to_string(), reservation.clone());
+        if let Err(err) = self.save() {
+            // Keep memory and file in sync
+            match previous {
+                Some(previous) => self.entries.insert(subdomain.to_string(), previous),
+                None => self.entries.remove(subdomain),
+            };
+            return Err(err);
+        }
+        Ok(reservation)
+    }
+
+    /// Returns whether `subdomain` was reserved.
+    pub fn release(&mut self, subdomain: &str) -> Result<bool> {
+        let Some(previous) = self.entries.remove(subdomain) else {
+            return Ok(false);
+        };
+        if let Err(err) = self.save() {
+            self.entries.insert(subdomain.to_string(), previous);
+            return Err(err);
+        }
+        Ok(true)
+    }
+
+    /// Write the reservations to a temporary file and move it over the old one,
+    /// so a crash never leaves a truncated file behind.
+    fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        write_private(&tmp, &serde_json::to_vec_pretty(&self.entries)?)
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Write `data` to a new file only its owner can read, it holds the owner tokens.
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    // The mode only applies to a new file, a leftover one would keep its own
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Reservations;
+
+    #[test]
+    fn reservations_survive_reload() {
+        let path =
+            std::env::temp_dir().join(format!("lt-reservations-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut reservations = Reservations::load(path.clone()).unwrap();
+        reservations
+            .reserve("billing-dev", "secret".to_string())
+            .unwrap();
+        reservations.reserve("other", "token".to_string()).unwrap();
+        assert!(reservations.release("other").unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reservations = Reservations::load(path.clone()).unwrap();
+        assert!(reservations.is_owner("billing-dev", Some("secret")));
+        assert!(!reservations.is_owner("billing-dev", Some("guess")));
+        assert!(!reservations.is_owner("billing-dev", None));
+        assert!(reservations.get("other").is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn readable_files_are_restricted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!(
+            "lt-reservations-readable-{}.json",
+            std::process::id()
+        ));
+        let tmp = std::path::PathBuf::from(format!("{}.tmp", path.display()));
+        let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        for (path, content) in [
+            (&path, r#"{"old":{"token":"a","created_at":0}}"#),
+            (&tmp, ""),
+        ] {
+            std::fs::write(path, content).unwrap();
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        let mut reservations = Reservations::load(path.clone()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        reservations.reserve("demo", "secret".to_string()).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert!(!tmp.exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,18 +1,30 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
-    sync::Arc,
-    time::{Duration, Instant},
+    net::IpAddr,
+    sync::{Arc, Weak},
+    time::{Duration, Instant, SystemTime},
 };
 
-use crate::AuthMode;
+use crate::auth::Auth;
+use crate::auth_limit::FailureLimiter;
+use crate::error::ServerError;
+use crate::handshake::{authenticate, constant_time_eq, Handshake};
+use crate::metrics::METRICS;
+use crate::mux::MuxSession;
+use crate::reminder::VisitorGate;
+use crate::reservations::{Reservation, Reservations};
+use crate::stream::{Traffic, TunnelStream};
+use crate::types::TunnelType;
+use anyhow::Result;
+use rand::{distributions::Alphanumeric, Rng};
 use socket2::{SockRef, TcpKeepalive};
 use tokio::{
     io::Interest,
     net::{TcpListener, TcpStream},
-    sync::Mutex,
-    task::JoinHandle,
-    time::timeout,
+    sync::{Mutex, Notify, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::{interval_at, timeout},
 };
 
 // See https://tldp.org/HOWTO/html_single/TCP-Keepalive-HOWTO to understand how keepalive work.
@@ -23,12 +35,31 @@ const TCP_KEEPALIVE_RETRIES: u32 = 5;
 
 /// How long before an unused client is cleaned up.
 const CLEANUP_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// A client without pooled sockets still holds its subdomain for this long after its last activity.
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often pooled sockets are checked for having been closed by the client.
+const SOCKET_SWEEP_INTERVAL: Duration = Duration::from_secs(20);
+/// Tunnel sockets allowed in the handshake per socket of a client, any more are dropped.
+const HANDSHAKES_PER_SOCKET: usize = 2;
+/// Length of the tokens which prove ownership of a subdomain and of its sockets.
+const TOKEN_LEN: usize = 32;
+
+/// How visitors wait for a tunnel connection while all of them are in use.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketWait {
+    /// Give up after this long, zero fails at once.
+    pub timeout: Duration,
+    /// Visitors allowed to wait per tunnel, any more are turned away at once.
+    pub max_waiting: usize,
+}
 
 /// App state holds all the client connection and status info.
 pub struct State {
     pub manager: Arc<Mutex<ClientManager>>,
-    pub max_sockets: u8,
-    pub auth_mode: AuthMode,
+    pub auth: Arc<dyn Auth>,
+    pub auth_failures: FailureLimiter,
+    /// Take the caller address from `X-Forwarded-For`, set by a reverse proxy in front of the api.
+    pub trust_forwarded_headers: bool,
     pub secure: bool,
     pub domain: String,
 }
@@ -37,30 +68,162 @@ pub struct ClientManager {
     pub clients: HashMap<String, Arc<Mutex<Client>>>,
     pub tunnels: u16,
     pub default_max_sockets: u8,
+    /// Accept tunnel sockets from clients which don't know the socket handshake.
+    pub allow_legacy_sockets: bool,
+    /// Subdomains nobody may register, set through the admin api.
+    pub blocked: HashSet<String>,
+    pub socket_wait: SocketWait,
+    /// Subdomains only their owner may register.
+    pub reservations: Reservations,
 }
 
 impl ClientManager {
-    pub fn new(max_sockets: u8) -> Self {
+    pub fn new(
+        max_sockets: u8,
+        allow_legacy_sockets: bool,
+        socket_wait: SocketWait,
+        reservations: Reservations,
+    ) -> Self {
         ClientManager {
             clients: HashMap::new(),
             tunnels: 0,
             default_max_sockets: max_sockets,
+            allow_legacy_sockets,
+            blocked: HashSet::new(),
+            socket_wait,
+            reservations,
         }
     }
 
-    pub async fn put(&mut self, url: &str) -> io::Result<u16> {
-        let client = Arc::new(Mutex::new(Client::new(self.default_max_sockets)));
-        self.clients.insert(url.to_string(), client.clone());
-        self.tunnels += 1;
+    /// Register a client for the subdomain `url`.
+    ///
+    /// A subdomain held by a live client is only handed over when `reclaim_token` matches
+    /// the token issued to that client, so its owner can get it back after a reconnect.
+    /// A reserved subdomain is only registered with its owner token as `reclaim_token`,
+    /// which is then issued as the reclaim token of the client.
+    /// TCP tunnels get a public port of their own, their subdomain only names them.
+    /// `client_ip` is where the registration came from, it doubles as the tunnel password.
+    /// `max_sockets` lowers the default limit of sockets for this client.
+    pub async fn put(
+        &mut self,
+        url: &str,
+        reclaim_token: Option<&str>,
+        tunnel_type: TunnelType,
+        client_ip: Option<IpAddr>,
+        max_sockets: Option<u8>,
+    ) -> Result<Registration> {
+        if self.blocked.contains(url) {
+            return Err(ServerError::SubdomainBlocked.into());
+        }
+        let reservation = self.reservations.get(url).cloned();
+        let reserved_owner = self.reservations.is_owner(url, reclaim_token);
+        if reservation.is_some() && !reserved_owner {
+            return Err(ServerError::SubdomainReserved.into());
+        }
+        if let Some(existing) = self.clients.get(url) {
+            let existing = existing.lock().await;
+            let is_owner = reserved_owner
+                || reclaim_token.is_some_and(|token| {
+                    constant_time_eq(token.as_bytes(), existing.reclaim_token.as_bytes())
+                });
+            if !is_owner && existing.is_live().await {
+                return Err(ServerError::SubdomainInUse.into());
+            }
+            log::debug!("Replacing client {url}, owner: {is_owner}");
+        }
 
-        let mut client = client.lock().await;
-        client.listen().await
+        let max_sockets = max_sockets.map_or(self.default_max_sockets, |max| {
+            max.clamp(1, self.default_max_sockets)
+        });
+        let mut client = Client::new(max_sockets, tunnel_type, client_ip, self.socket_wait);
+        if let Some(reservation) = reservation {
+            client.reclaim_token = reservation.token;
+        }
+        let port = client.listen(self.allow_legacy_sockets).await?;
+        let mut registration = Registration {
+            port,
+            public_port: None,
+            max_sockets,
+            reclaim_token: client.reclaim_token.clone(),
+            socket_token: client.socket_token.clone(),
+        };
+
+        let client = Arc::new(Mutex::new(client));
+        if tunnel_type == TunnelType::Tcp {
+            registration.public_port = Some(Client::listen_public(&client).await?);
+        }
+        match self.clients.insert(url.to_string(), client) {
+            // Free its port and sockets now rather than at the next cleanup
+            Some(replaced) => replaced.lock().await.close().await,
+            None => self.tunnels += 1,
+        }
+
+        Ok(registration)
     }
 
     pub fn get_client(&self, id: &str) -> Option<Arc<Mutex<Client>>> {
         self.clients.get(id).cloned()
     }
 
+    /// Disconnect a client and forget it, returns whether it was registered.
+    pub async fn kick(&mut self, url: &str) -> bool {
+        let Some(client) = self.clients.remove(url) else {
+            return false;
+        };
+        self.tunnels = self.tunnels.saturating_sub(1);
+        client.lock().await.close().await;
+        log::info!("Kicked client {url}");
+        true
+    }
+
+    /// Refuse to register `url` from now on, and kick its current client.
+    pub async fn block(&mut self, url: &str) {
+        self.blocked.insert(url.to_string());
+        self.kick(url).await;
+    }
+
+    /// Returns whether `url` was blocked.
+    pub fn unblock(&mut self, url: &str) -> bool {
+        self.blocked.remove(url)
+    }
+
+    /// Reserve `url` for the holder of `token`, a new random token if `None`,
+    /// and kick its current client unless it registered with that token.
+    pub async fn reserve(&mut self, url: &str, token: Option<String>) -> Result<Reservation> {
+        let token = token.unwrap_or_else(|| random_token(TOKEN_LEN));
+        let reservation = self.reservations.reserve(url, token)?;
+
+        let is_owner = match self.clients.get(url) {
+            Some(client) => constant_time_eq(
+                client.lock().await.reclaim_token.as_bytes(),
+                reservation.token.as_bytes(),
+            ),
+            None => true,
+        };
+        if !is_owner {
+            self.kick(url).await;
+        }
+        Ok(reservation)
+    }
+
+    /// Kick the client registered for `url` once `ttl` is over, unless it has been replaced by then.
+    pub async fn expire(
+        manager: Arc<Mutex<ClientManager>>,
+        url: String,
+        socket_token: String,
+        ttl: Duration,
+    ) {
+        tokio::time::sleep(ttl).await;
+        let mut manager = manager.lock().await;
+        let Some(client) = manager.get_client(&url) else {
+            return;
+        };
+        if client.lock().await.socket_token == socket_token {
+            log::info!("Tunnel {url} expired");
+            manager.kick(&url).await;
+        }
+    }
+
     /// clean up old unused clients
     pub async fn cleanup(&mut self) {
         let mut to_remove = vec![];
@@ -82,82 +245,143 @@ impl ClientManager {
     }
 }
 
+/// What a client needs to connect to its tunnel.
+#[derive(Debug)]
+pub struct Registration {
+    pub port: u16,
+    /// Where visitors connect to a TCP tunnel.
+    pub public_port: Option<u16>,
+    pub max_sockets: u8,
+    pub reclaim_token: String,
+    pub socket_token: String,
+}
+
 pub struct Client {
     pub available_sockets: Arc<Mutex<Vec<TcpStream>>>,
+    /// Multiplexed session of a client which asked for one, preferred over the socket pool.
+    pub mux: Arc<Mutex<Option<MuxSession>>>,
     pub port: Option<u16>,
+    /// Where visitors connect to a TCP tunnel.
+    pub public_port: Option<u16>,
     pub max_sockets: u8,
+    pub tunnel_type: TunnelType,
+    /// Address the tunnel was registered from.
+    pub client_ip: Option<IpAddr>,
+    pub created_at: SystemTime,
+    pub traffic: Arc<Traffic>,
+    /// Lets visitors past the reminder page, when it is enabled.
+    pub visitor_gate: Option<Arc<VisitorGate>>,
+    /// Proves ownership of the subdomain when registering it again.
+    pub reclaim_token: String,
+    /// Presented by the client on every socket it opens to `port`.
+    pub socket_token: String,
+    /// Visitors waiting for a connection once the pool is empty.
+    wait_queue: Arc<WaitQueue>,
     listen_task: Option<JoinHandle<()>>,
+    /// Accepts the visitors of a TCP tunnel.
+    public_task: Option<JoinHandle<()>>,
     /// last time a new connection was established
     last_connection_time: Instant,
 }
 
 impl Client {
-    pub fn new(max_sockets: u8) -> Self {
+    pub fn new(
+        max_sockets: u8,
+        tunnel_type: TunnelType,
+        client_ip: Option<IpAddr>,
+        socket_wait: SocketWait,
+    ) -> Self {
+        let visitor_gate = client_ip.map(|ip| {
+            Arc::new(VisitorGate {
+                password: ip.to_string(),
+                visitor_token: random_token(TOKEN_LEN),
+            })
+        });
         Client {
             available_sockets: Arc::new(Mutex::new(vec![])),
+            mux: Arc::new(Mutex::new(None)),
             port: None,
+            public_port: None,
             max_sockets,
+            tunnel_type,
+            client_ip,
+            created_at: SystemTime::now(),
+            traffic: Arc::new(Traffic::default()),
+            visitor_gate,
+            reclaim_token: random_token(TOKEN_LEN),
+            socket_token: random_token(TOKEN_LEN),
+            wait_queue: Arc::new(WaitQueue::new(socket_wait)),
             listen_task: None,
+            public_task: None,
             last_connection_time: std::time::Instant::now(),
         }
     }
 
-    pub async fn listen(&mut self) -> io::Result<u16> {
+    pub async fn listen(&mut self, allow_legacy_sockets: bool) -> io::Result<u16> {
         let listener = TcpListener::bind("0.0.0.0:0").await?;
         let port = listener.local_addr()?.port();
         self.port = Some(port);
 
         let sockets = self.available_sockets.clone();
+        let mux = self.mux.clone();
         let max_sockets = self.max_sockets;
+        let socket_token = Arc::new(self.socket_token.clone());
+        let wait_queue = self.wait_queue.clone();
 
         let listen_task = tokio::spawn(async move {
-            // TODO check client is authenticated for the port
+            // Handshakes run beside the accept loop, and are aborted together with it.
+            // Anyone may connect, so only a few at a time, counting multiplexed sessions.
+            let mut handshakes = JoinSet::new();
+            let max_handshakes = max_sockets as usize * HANDSHAKES_PER_SOCKET;
+            let mut sweep = interval_at(
+                tokio::time::Instant::now() + SOCKET_SWEEP_INTERVAL,
+                SOCKET_SWEEP_INTERVAL,
+            );
             loop {
-                match timeout(Duration::from_secs(20), listener.accept()).await {
-                    Ok(Ok((socket, addr))) => {
+                let accepted = tokio::select! {
+                    Some(_) = handshakes.join_next(), if !handshakes.is_empty() => continue,
+                    _ = sweep.tick() => {
+                        drop_disconnected(&sockets).await;
+                        continue;
+                    }
+                    accepted = listener.accept() => accepted,
+                };
+                match accepted {
+                    Ok((_, addr)) if handshakes.len() >= max_handshakes => {
+                        log::warn!("Drop tunnel socket {:?}, too many handshakes", addr);
+                    }
+                    Ok((mut socket, addr)) => {
                         log::info!("new client connection: {:?}", addr);
 
-                        let mut sockets = sockets.lock().await;
-                        let sockets_len = sockets.len();
-
-                        if sockets_len < max_sockets as usize {
-                            log::debug!("Add a new socket {}/{max_sockets}", sockets_len + 1,);
-
-                            let ka = TcpKeepalive::new()
-                                .with_time(TCP_KEEPALIVE_TIME)
-                                .with_interval(TCP_KEEPALIVE_INTERVAL);
-                            #[cfg(not(target_os = "windows"))]
-                            let ka = ka.with_retries(TCP_KEEPALIVE_RETRIES);
-                            let sf = SockRef::from(&socket);
-                            if let Err(err) = sf.set_tcp_keepalive(&ka) {
-                                log::warn!("failed to enable TCP keepalive: {err}");
+                        let sockets = sockets.clone();
+                        let mux = mux.clone();
+                        let socket_token = socket_token.clone();
+                        let wait_queue = wait_queue.clone();
+                        handshakes.spawn(async move {
+                            match authenticate(&mut socket, &socket_token, allow_legacy_sockets)
+                                .await
+                            {
+                                Ok(Handshake::Authenticated) => {}
+                                Ok(Handshake::Multiplexed) => {
+                                    log::debug!("Start multiplexed session {:?}", addr);
+                                    run_mux_session(&mux, socket, &wait_queue).await;
+                                    return;
+                                }
+                                Ok(Handshake::Legacy) => {
+                                    log::debug!("Accept unauthenticated legacy socket {:?}", addr)
+                                }
+                                Err(err) => {
+                                    log::warn!("Reject tunnel socket {:?}: {}", addr, err);
+                                    METRICS.record_error(&err);
+                                    return;
+                                }
                             }
-
-                            sockets.push(socket)
-                        } else {
-                            log::warn!("Reached sockets max: {sockets_len}/{max_sockets}");
-                        }
-                    }
-                    Ok(Err(e)) => log::info!("Couldn't get client: {:?}", e),
-                    Err(_) => {
-                        // timeout clean up timeout connections
-                        let mut sockets = sockets.lock().await;
-                        let sockets_len = sockets.len();
-                        let mut connected_sockets = vec![];
-                        while let Some(s) = sockets.pop() {
-                            if socket_is_writable(&s).await {
-                                connected_sockets.push(s);
+                            if add_socket(&sockets, socket, max_sockets).await {
+                                wait_queue.available.notify_one();
                             }
-                        }
-
-                        if sockets_len != connected_sockets.len() {
-                            log::debug!(
-                                "removed {} old disconnected sockets",
-                                sockets_len - connected_sockets.len()
-                            );
-                        }
-                        *sockets = connected_sockets;
+                        });
                     }
+                    Err(e) => log::info!("Couldn't get client: {:?}", e),
                 }
             }
         });
@@ -166,8 +390,32 @@ impl Client {
         Ok(port)
     }
 
-    pub async fn take(&mut self) -> Option<TcpStream> {
+    /// Listen for the visitors of a TCP tunnel, and splice each onto a tunnel connection.
+    pub async fn listen_public(client: &Arc<Mutex<Client>>) -> io::Result<u16> {
+        let listener = TcpListener::bind("0.0.0.0:0").await?;
+        let port = listener.local_addr()?.port();
+        log::info!("TCP tunnel listens at {}", port);
+
+        // Only a weak reference, the task is aborted once the client is dropped
+        let public_task = tokio::spawn(serve_public(listener, Arc::downgrade(client)));
+        let mut client = client.lock().await;
+        client.public_task = Some(public_task);
+        client.public_port = Some(port);
+
+        Ok(port)
+    }
+
+    pub async fn take(&mut self) -> Option<TunnelStream> {
         self.last_connection_time = Instant::now();
+
+        let session = self.mux.lock().await.clone();
+        if let Some(session) = session {
+            match session.open().await {
+                Ok(stream) => return Some(TunnelStream::mux(stream, self.traffic.clone())),
+                Err(err) => log::warn!("Failed to open a multiplexed stream: {}", err),
+            }
+        }
+
         let mut sockets = self.available_sockets.lock().await;
 
         let sockets_len = sockets.len();
@@ -179,7 +427,7 @@ impl Client {
             );
 
             if socket_is_writable(&socket).await {
-                return Some(socket);
+                return Some(TunnelStream::tcp(socket, self.traffic.clone()));
             }
 
             log::warn!(
@@ -192,11 +440,71 @@ impl Client {
         None
     }
 
+    /// Take a connection to the client, waiting for one to be returned to the pool if all are in use.
+    ///
+    /// The client lock is only held while trying the pool, so visitors wait beside each other.
+    /// Fails with [`ServerError::TunnelBusy`] once the wait times out or too many visitors wait.
+    pub async fn connect(client: &Mutex<Client>) -> Result<TunnelStream> {
+        let wait_queue = {
+            let mut client = client.lock().await;
+            if let Some(stream) = client.take().await {
+                return Ok(stream);
+            }
+            client.wait_queue.clone()
+        };
+        if wait_queue.timeout.is_zero() {
+            return Err(ServerError::EmptyConnection.into());
+        }
+        let Ok(_slot) = wait_queue.slots.try_acquire() else {
+            log::warn!("Too many visitors waiting for a tunnel connection");
+            return Err(ServerError::TunnelBusy.into());
+        };
+
+        let wait = async {
+            loop {
+                wait_queue.available.notified().await;
+                if let Some(stream) = client.lock().await.take().await {
+                    return stream;
+                }
+            }
+        };
+        timeout(wait_queue.timeout, wait)
+            .await
+            .map_err(|_| ServerError::TunnelBusy.into())
+    }
+
+    /// When a visitor last went through the tunnel, or it was registered.
+    pub fn last_activity(&self) -> SystemTime {
+        SystemTime::now() - self.last_connection_time.elapsed()
+    }
+
+    /// Stop accepting tunnel connections and visitors, and close the pooled sockets.
+    /// Connections already handed out to visitors are left to finish.
+    pub async fn close(&mut self) {
+        if let Some(task) = self.listen_task.take() {
+            task.abort();
+        }
+        if let Some(task) = self.public_task.take() {
+            task.abort();
+        }
+        self.available_sockets.lock().await.clear();
+        self.mux.lock().await.take();
+    }
+
     /// If the client has not been used for a while and so should be cleaned up.
     pub async fn should_cleanup(&self) -> bool {
         let sockets = self.available_sockets.lock().await;
+        let multiplexed = self.mux.lock().await.is_some();
 
-        sockets.is_empty() && self.last_connection_time.elapsed() > CLEANUP_TIMEOUT
+        sockets.is_empty() && !multiplexed && self.last_connection_time.elapsed() > CLEANUP_TIMEOUT
+    }
+
+    /// If the client is still connected, or was active so recently that it may reconnect.
+    pub async fn is_live(&self) -> bool {
+        let sockets = self.available_sockets.lock().await;
+        let multiplexed = self.mux.lock().await.is_some();
+
+        !sockets.is_empty() || multiplexed || self.last_connection_time.elapsed() < LIVENESS_TIMEOUT
     }
 
     /// Get statistics about this client's connected sockets
@@ -204,6 +512,7 @@ impl Client {
         let sockets = self.available_sockets.lock().await;
         ClientStats {
             connected_sockets: sockets.len(),
+            multiplexed: self.mux.lock().await.is_some(),
         }
     }
 }
@@ -211,6 +520,8 @@ impl Client {
 #[derive(Debug)]
 pub struct ClientStats {
     pub connected_sockets: usize,
+    /// Whether a multiplexed session is connected.
+    pub multiplexed: bool,
 }
 
 impl Drop for Client {
@@ -218,6 +529,147 @@ impl Drop for Client {
         if let Some(task) = self.listen_task.take() {
             task.abort();
         }
+        if let Some(task) = self.public_task.take() {
+            task.abort();
+        }
+    }
+}
+
+fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Visitors waiting for a tunnel connection, woken as the client adds sockets.
+struct WaitQueue {
+    available: Notify,
+    /// A permit for each visitor allowed to wait.
+    slots: Semaphore,
+    timeout: Duration,
+}
+
+impl WaitQueue {
+    fn new(socket_wait: SocketWait) -> Self {
+        WaitQueue {
+            available: Notify::new(),
+            slots: Semaphore::new(socket_wait.max_waiting),
+            timeout: socket_wait.timeout,
+        }
+    }
+}
+
+/// Put an authenticated socket into the pool, unless it is full. Returns whether it was added.
+/// Drop the pooled sockets the client has closed.
+async fn drop_disconnected(sockets: &Mutex<Vec<TcpStream>>) {
+    let mut sockets = sockets.lock().await;
+    let sockets_len = sockets.len();
+    let mut connected_sockets = vec![];
+    while let Some(s) = sockets.pop() {
+        if socket_is_writable(&s).await {
+            connected_sockets.push(s);
+        }
+    }
+
+    if sockets_len != connected_sockets.len() {
+        log::debug!(
+            "removed {} old disconnected sockets",
+            sockets_len - connected_sockets.len()
+        );
+    }
+    *sockets = connected_sockets;
+}
+
+async fn add_socket(sockets: &Mutex<Vec<TcpStream>>, socket: TcpStream, max_sockets: u8) -> bool {
+    let mut sockets = sockets.lock().await;
+    let sockets_len = sockets.len();
+
+    if sockets_len < max_sockets as usize {
+        log::debug!("Add a new socket {}/{max_sockets}", sockets_len + 1,);
+        set_keepalive(&socket);
+        sockets.push(socket);
+        true
+    } else {
+        log::warn!("Reached sockets max: {sockets_len}/{max_sockets}");
+        false
+    }
+}
+
+async fn serve_public(listener: TcpListener, client: Weak<Mutex<Client>>) {
+    loop {
+        let (mut visitor, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                log::info!("Couldn't accept TCP visitor: {:?}", err);
+                continue;
+            }
+        };
+        let Some(client) = client.upgrade() else {
+            return;
+        };
+        log::debug!("New TCP visitor {:?}", addr);
+
+        tokio::spawn(async move {
+            let stream = Client::connect(&client).await;
+            drop(client);
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::warn!("No tunnel connection for TCP visitor {:?}: {}", addr, err);
+                    return;
+                }
+            };
+            match tokio::io::copy_bidirectional(&mut visitor, &mut stream).await {
+                Ok((sent, received)) => {
+                    log::debug!(
+                        "TCP visitor {:?} done, sent {sent} bytes, received {received} bytes",
+                        addr
+                    )
+                }
+                Err(err) => log::debug!("TCP visitor {:?} failed: {:?}", addr, err),
+            }
+        });
+    }
+}
+
+/// Serve a multiplexed session until the client disconnects, replacing any previous session.
+async fn run_mux_session(
+    mux: &Mutex<Option<MuxSession>>,
+    socket: TcpStream,
+    wait_queue: &WaitQueue,
+) {
+    set_keepalive(&socket);
+    let (session, driver) = MuxSession::new(socket);
+    if mux.lock().await.replace(session.clone()).is_some() {
+        log::debug!("Replace the previous multiplexed session");
+    }
+    // The session serves every waiting visitor, and any about to wait
+    wait_queue.available.notify_waiters();
+    wait_queue.available.notify_one();
+
+    driver.await;
+
+    let mut mux = mux.lock().await;
+    if mux
+        .as_ref()
+        .is_some_and(|current| current.same_session(&session))
+    {
+        *mux = None;
+    }
+    log::debug!("Multiplexed session closed");
+}
+
+fn set_keepalive(socket: &TcpStream) {
+    let ka = TcpKeepalive::new()
+        .with_time(TCP_KEEPALIVE_TIME)
+        .with_interval(TCP_KEEPALIVE_INTERVAL);
+    #[cfg(not(target_os = "windows"))]
+    let ka = ka.with_retries(TCP_KEEPALIVE_RETRIES);
+    let sf = SockRef::from(socket);
+    if let Err(err) = sf.set_tcp_keepalive(&ka) {
+        log::warn!("failed to enable TCP keepalive: {err}");
     }
 }
 
@@ -229,3 +681,272 @@ async fn socket_is_writable(socket: &TcpStream) -> bool {
         .map(|ready| !ready.is_write_closed())
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::Mutex,
+        time::timeout,
+    };
+
+    use super::{Client, ClientManager, SocketWait, HANDSHAKES_PER_SOCKET, LIVENESS_TIMEOUT};
+    use crate::error::ServerError;
+    use crate::reservations::Reservations;
+    use crate::types::TunnelType;
+
+    fn manager() -> ClientManager {
+        let socket_wait = SocketWait {
+            timeout: Duration::ZERO,
+            max_waiting: 0,
+        };
+        ClientManager::new(10, false, socket_wait, Reservations::default())
+    }
+
+    #[tokio::test]
+    async fn put_caps_max_sockets() {
+        let mut manager = manager();
+        for (id, max_sockets, capped) in [("a", None, 10), ("b", Some(3), 3), ("c", Some(255), 10)]
+        {
+            let registration = manager
+                .put(id, None, TunnelType::Http, None, max_sockets)
+                .await
+                .unwrap();
+            assert_eq!(registration.max_sockets, capped);
+        }
+    }
+
+    #[tokio::test]
+    async fn put_protects_live_clients() {
+        let mut manager = manager();
+        let first = manager
+            .put("demo", None, TunnelType::Http, None, None)
+            .await
+            .unwrap();
+        assert_eq!(manager.tunnels, 1);
+
+        let taken = manager
+            .put("demo", None, TunnelType::Http, None, None)
+            .await;
+        assert!(matches!(
+            taken.unwrap_err().downcast_ref(),
+            Some(ServerError::SubdomainInUse)
+        ));
+        let wrong = manager
+            .put("demo", Some("guess"), TunnelType::Http, None, None)
+            .await;
+        assert!(matches!(
+            wrong.unwrap_err().downcast_ref(),
+            Some(ServerError::SubdomainInUse)
+        ));
+        let socket_token = manager
+            .get_client("demo")
+            .unwrap()
+            .lock()
+            .await
+            .socket_token
+            .clone();
+        assert_eq!(socket_token, first.socket_token);
+
+        // The owner gets it back, with new tokens
+        let replaced = manager.get_client("demo").unwrap();
+        let reclaimed = manager
+            .put(
+                "demo",
+                Some(&first.reclaim_token),
+                TunnelType::Http,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_ne!(reclaimed.reclaim_token, first.reclaim_token);
+        assert_ne!(reclaimed.socket_token, first.socket_token);
+        assert_eq!(manager.tunnels, 1);
+        assert!(replaced.lock().await.listen_task.is_none());
+
+        // A client gone quiet for long enough is up for grabs
+        manager
+            .get_client("demo")
+            .unwrap()
+            .lock()
+            .await
+            .last_connection_time = Instant::now() - LIVENESS_TIMEOUT * 2;
+        manager
+            .put("demo", None, TunnelType::Http, None, Some(3))
+            .await
+            .unwrap();
+        assert_eq!(manager.tunnels, 1);
+        assert_eq!(
+            manager.get_client("demo").unwrap().lock().await.max_sockets,
+            3
+        );
+
+        manager
+            .put("other", None, TunnelType::Http, None, Some(50))
+            .await
+            .unwrap();
+        assert_eq!(manager.tunnels, 2);
+        assert_eq!(
+            manager
+                .get_client("other")
+                .unwrap()
+                .lock()
+                .await
+                .max_sockets,
+            10
+        );
+    }
+
+    #[tokio::test]
+    async fn expire_kicks_the_same_client() {
+        let manager = Arc::new(Mutex::new(manager()));
+        let first = manager
+            .lock()
+            .await
+            .put("demo", None, TunnelType::Http, None, None)
+            .await
+            .unwrap();
+        let expired = tokio::spawn(ClientManager::expire(
+            manager.clone(),
+            "demo".to_string(),
+            first.socket_token.clone(),
+            Duration::from_millis(50),
+        ));
+        // A new registration isn't cut short by the previous one expiring
+        let second = manager
+            .lock()
+            .await
+            .put(
+                "demo",
+                Some(&first.reclaim_token),
+                TunnelType::Http,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        expired.await.unwrap();
+        assert!(manager.lock().await.get_client("demo").is_some());
+
+        ClientManager::expire(
+            manager.clone(),
+            "demo".to_string(),
+            second.socket_token,
+            Duration::from_millis(10),
+        )
+        .await;
+        let manager = manager.lock().await;
+        assert!(manager.get_client("demo").is_none());
+        assert_eq!(manager.tunnels, 0);
+    }
+
+    #[tokio::test]
+    async fn listen_limits_handshakes() {
+        let mut client = waiting_client(0);
+        let port = client.listen(false).await.unwrap();
+
+        let mut handshaking = vec![];
+        for _ in 0..HANDSHAKES_PER_SOCKET {
+            handshaking.push(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        let mut dropped = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = timeout(Duration::from_secs(1), dropped.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        // The others are still waiting for their token
+        for socket in handshaking.iter_mut() {
+            let read = timeout(Duration::from_millis(100), socket.read(&mut buf)).await;
+            assert!(read.is_err());
+        }
+    }
+
+    fn waiting_client(max_waiting: usize) -> Client {
+        let socket_wait = SocketWait {
+            timeout: Duration::from_millis(100),
+            max_waiting,
+        };
+        Client::new(1, TunnelType::Http, None, socket_wait)
+    }
+
+    #[tokio::test]
+    async fn connect_times_out() {
+        let client = Mutex::new(waiting_client(1));
+        let started = Instant::now();
+        let result = Client::connect(&client).await;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref(),
+            Some(ServerError::TunnelBusy)
+        ));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        // Without a wait the pool being empty is reported at once
+        let client = Mutex::new(Client::new(
+            1,
+            TunnelType::Http,
+            None,
+            SocketWait {
+                timeout: Duration::ZERO,
+                max_waiting: 1,
+            },
+        ));
+        let result = Client::connect(&client).await;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref(),
+            Some(ServerError::EmptyConnection)
+        ));
+    }
+
+    #[tokio::test]
+    async fn connect_limits_waiting() {
+        let client = Arc::new(Mutex::new(waiting_client(1)));
+        let waiting = tokio::spawn({
+            let client = client.clone();
+            async move { Client::connect(&client).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let started = Instant::now();
+        let result = Client::connect(&client).await;
+        assert!(matches!(
+            result.err().unwrap().downcast_ref(),
+            Some(ServerError::TunnelBusy)
+        ));
+        assert!(started.elapsed() < Duration::from_millis(50));
+        assert!(waiting.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_wakes_with_new_socket() {
+        let mut client = waiting_client(1);
+        let port = client.listen(false).await.unwrap();
+        let socket_token = client.socket_token.clone();
+        let client = Arc::new(Mutex::new(client));
+        let waiting = tokio::spawn({
+            let client = client.clone();
+            async move { Client::connect(&client).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        socket
+            .write_all(format!("LT-AUTH {}\n", socket_token).as_bytes())
+            .await
+            .unwrap();
+        assert!(waiting.await.unwrap().is_ok());
+        assert!(client
+            .lock()
+            .await
+            .available_sockets
+            .lock()
+            .await
+            .is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_util::compat::Compat;
+
+use crate::metrics::METRICS;
+
+/// Bytes relayed through a tunnel, over all its connections.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Sent to the client, i.e. the requests of visitors.
+    pub sent: AtomicU64,
+    /// Received from the client, i.e. the responses of the local service.
+    pub received: AtomicU64,
+}
+
+/// A connection to the client, either a pooled socket or a stream of a multiplexed session.
+pub struct TunnelStream {
+    io: TunnelIo,
+    traffic: Arc<Traffic>,
+}
+
+enum TunnelIo {
+    Tcp(TcpStream),
+    Mux(Compat<yamux::Stream>),
+}
+
+impl TunnelStream {
+    pub fn tcp(stream: TcpStream, traffic: Arc<Traffic>) -> Self {
+        TunnelStream {
+            io: TunnelIo::Tcp(stream),
+            traffic,
+        }
+    }
+
+    pub fn mux(stream: Compat<yamux::Stream>, traffic: Arc<Traffic>) -> Self {
+        TunnelStream {
+            io: TunnelIo::Mux(stream),
+            traffic,
+        }
+    }
+
+    /// Which connection of the client this is, for logs.
+    pub fn describe(&self) -> String {
+        match &self.io {
+            TunnelIo::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => format!("socket {}", addr),
+                Err(_) => "socket".to_string(),
+            },
+            TunnelIo::Mux(stream) => format!("stream {}", stream.get_ref().id()),
+        }
+    }
+}
+
+impl AsyncRead for TunnelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = match &mut this.io {
+            TunnelIo::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            TunnelIo::Mux(stream) => Pin::new(stream).poll_read(cx, buf),
+        };
+        let read = buf.filled().len() - before;
+        this.traffic
+            .received
+            .fetch_add(read as u64, Ordering::Relaxed);
+        METRICS.record_traffic(0, read as u64);
+        res
+    }
+}
+
+impl AsyncWrite for TunnelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let res = match &mut this.io {
+            TunnelIo::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            TunnelIo::Mux(stream) => Pin::new(stream).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(written)) = res {
+            this.traffic
+                .sent
+                .fetch_add(written as u64, Ordering::Relaxed);
+            METRICS.record_traffic(written as u64, 0);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            TunnelIo::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            TunnelIo::Mux(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().io {
+            TunnelIo::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            TunnelIo::Mux(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
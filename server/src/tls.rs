@@ -0,0 +1,215 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// How often the certificate files are checked for changes.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Certificate to serve HTTPS and WSS on the proxy port,
+/// usually a wildcard certificate for `*.domain`.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf certificate first.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key.
+    pub key_path: PathBuf,
+}
+
+/// Hands out the certificate loaded last, so a renewed one is used without a restart.
+#[derive(Debug)]
+struct ReloadingCert {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .expect("Certificate lock poisoned")
+                .clone(),
+        )
+    }
+}
+
+/// Load the certificate and keep reloading it whenever its files change.
+pub fn acceptor(config: TlsConfig) -> Result<TlsAcceptor> {
+    let provider = Arc::new(ring::default_provider());
+    let key = load(&config, &provider)?;
+    let cert = Arc::new(ReloadingCert {
+        current: RwLock::new(Arc::new(key)),
+    });
+
+    let mut server_config = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    // The proxy only speaks HTTP/1.1 to the tunnels
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    tokio::spawn(watch(config, provider, cert));
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn load(config: &TlsConfig, provider: &CryptoProvider) -> Result<CertifiedKey> {
+    let certs = CertificateDer::pem_file_iter(&config.cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("Failed to read certificate {}", config.cert_path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificate in {}", config.cert_path.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key_path)
+        .with_context(|| format!("Failed to read private key {}", config.key_path.display()))?;
+
+    Ok(CertifiedKey::from_der(certs, key, provider)?)
+}
+
+/// Poll the modification time of the files, a failed reload keeps the previous certificate.
+async fn watch(config: TlsConfig, provider: Arc<CryptoProvider>, cert: Arc<ReloadingCert>) {
+    let mut loaded = modified_at(&config);
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let modified = modified_at(&config);
+        if modified == loaded {
+            continue;
+        }
+
+        match load(&config, &provider) {
+            Ok(key) => {
+                *cert.current.write().expect("Certificate lock poisoned") = Arc::new(key);
+                loaded = modified;
+                log::info!("Reloaded TLS certificate {}", config.cert_path.display());
+            }
+            // Retried on the next check, e.g. when only one of the files was replaced yet
+            Err(err) => log::warn!("Failed to reload TLS certificate: {:?}", err),
+        }
+    }
+}
+
+fn modified_at(config: &TlsConfig) -> Option<(SystemTime, SystemTime)> {
+    let cert = config
+        .cert_path
+        .metadata()
+        .and_then(|m| m.modified())
+        .ok()?;
+    let key = config.key_path.metadata().and_then(|m| m.modified()).ok()?;
+    Some((cert, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{Arc, RwLock},
+        time::{Duration, SystemTime},
+    };
+
+    use rustls::crypto::ring;
+
+    use super::{load, watch, ReloadingCert, TlsConfig, RELOAD_CHECK_INTERVAL};
+
+    /// Self-signed `*.localhost` certificates `a` and `b`.
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    fn config(cert: &str, key: &str) -> TlsConfig {
+        TlsConfig {
+            cert_path: fixture(cert),
+            key_path: fixture(key),
+        }
+    }
+
+    #[test]
+    fn load_works() {
+        let provider = ring::default_provider();
+        let key = load(&config("tls-a.crt", "tls-a.key"), &provider).unwrap();
+        assert_eq!(key.cert.len(), 1);
+
+        assert!(load(&config("tls-a.crt", "tls-b.key"), &provider).is_err());
+        assert!(load(&config("tls-a.crt", "missing.key"), &provider).is_err());
+        assert!(load(&config("missing.crt", "tls-a.key"), &provider).is_err());
+        // A key is no certificate
+        assert!(load(&config("tls-a.key", "tls-a.key"), &provider).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_reloads_changed_files() {
+        let dir = std::env::temp_dir().join(format!("lt-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files = TlsConfig {
+            cert_path: dir.join("tls.crt"),
+            key_path: dir.join("tls.key"),
+        };
+        let copy = |from: &str, to: &PathBuf, modified: SystemTime| {
+            fs::copy(fixture(from), to).unwrap();
+            fs::File::options()
+                .write(true)
+                .open(to)
+                .unwrap()
+                .set_modified(modified)
+                .unwrap();
+        };
+        let install = |name: &str, modified: SystemTime| {
+            copy(&format!("tls-{}.crt", name), &files.cert_path, modified);
+            copy(&format!("tls-{}.key", name), &files.key_path, modified);
+        };
+        let provider = Arc::new(ring::default_provider());
+        let served = |cert: &ReloadingCert| {
+            cert.current
+                .read()
+                .unwrap()
+                .end_entity_cert()
+                .unwrap()
+                .to_vec()
+        };
+        let expected = |name: &str| {
+            load(
+                &config(&format!("tls-{}.crt", name), &format!("tls-{}.key", name)),
+                &provider,
+            )
+            .unwrap()
+            .end_entity_cert()
+            .unwrap()
+            .to_vec()
+        };
+
+        let started = SystemTime::now();
+        install("a", started);
+        let cert = Arc::new(ReloadingCert {
+            current: RwLock::new(Arc::new(load(&files, &provider).unwrap())),
+        });
+        tokio::spawn(watch(files.clone(), provider.clone(), cert.clone()));
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        // A half replaced pair keeps the old certificate
+        copy(
+            "tls-b.crt",
+            &files.cert_path,
+            started + Duration::from_secs(60),
+        );
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+        assert_eq!(served(&cert), expected("a"));
+
+        install("b", started + Duration::from_secs(120));
+        tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+        assert_eq!(served(&cert), expected("b"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
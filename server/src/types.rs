@@ -1,17 +1,31 @@
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Default)]
 #[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
 pub enum AuthMode {
+    #[default]
     NOAUTH,
     CLOUDFLARE,
     PLAINTEXT,
+    /// Per-user bcrypt passwords from the file at `HTPASSWD_FILE`.
+    HTPASSWD,
+    /// Signed tokens, verified with the key at `JWT_KEY_FILE` or `JWT_JWKS`.
+    JWT,
+    /// Decisions of the HTTP service at `AUTH_WEBHOOK_URL`.
+    WEBHOOK,
 }
 
-impl Default for AuthMode {
-    fn default() -> Self {
-        AuthMode::NOAUTH
-    }
+/// What a tunnel carries, requested by the client with the `type` query parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TunnelType {
+    /// HTTP requests routed by the `Host` header of the proxy port.
+    #[default]
+    Http,
+    /// Raw TCP connections on a dedicated public port.
+    Tcp,
 }
 
 impl fmt::Display for AuthMode {
@@ -20,6 +34,9 @@ impl fmt::Display for AuthMode {
             AuthMode::NOAUTH => write!(f, "NOAUTH"),
             AuthMode::CLOUDFLARE => write!(f, "CLOUDFLARE"),
             AuthMode::PLAINTEXT => write!(f, "PLAINTEXT"),
+            AuthMode::HTPASSWD => write!(f, "HTPASSWD"),
+            AuthMode::JWT => write!(f, "JWT"),
+            AuthMode::WEBHOOK => write!(f, "WEBHOOK"),
         }
     }
 }
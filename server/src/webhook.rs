@@ -0,0 +1,209 @@
+//! Asks an HTTP service of your own whether a client may register a tunnel.
+//!
+//! The server POSTs `{"subdomain", "credential", "client_ip"}` as JSON to the webhook, which answers
+//! `{"allow": true}`, optionally with `"max_sockets"` and a `"ttl"` in seconds for the tunnel.
+//! `"max_sockets"` is capped at the `max_sockets` of the server.
+//! A 401 or 403 status denies as well.
+
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{Auth, AuthDecision, AuthRequest, HTTP_CLIENT};
+
+#[derive(Serialize)]
+struct WebhookRequest<'a> {
+    subdomain: &'a str,
+    credential: Option<&'a str>,
+    client_ip: Option<IpAddr>,
+}
+
+#[derive(Deserialize)]
+struct WebhookResponse {
+    allow: bool,
+    /// Wider than the limit itself, so a large one is capped instead of failing the registration.
+    max_sockets: Option<u64>,
+    /// Seconds.
+    ttl: Option<u64>,
+}
+
+/// Forwards registrations to a webhook.
+pub struct WebhookAuth {
+    url: String,
+    /// Sent as bearer token, so the webhook can tell the server from anyone else.
+    token: Option<String>,
+}
+
+impl WebhookAuth {
+    pub fn new(url: String, token: Option<String>) -> Self {
+        WebhookAuth { url, token }
+    }
+}
+
+#[async_trait]
+impl Auth for WebhookAuth {
+    async fn authorize(&self, request: &AuthRequest) -> Result<bool> {
+        Ok(self.decide(request).await?.allowed)
+    }
+
+    async fn decide(&self, request: &AuthRequest) -> Result<AuthDecision> {
+        let mut call = HTTP_CLIENT.post(&self.url).json(&WebhookRequest {
+            subdomain: &request.subdomain,
+            credential: request.credential.as_deref(),
+            client_ip: request.client_ip,
+        });
+        if let Some(token) = &self.token {
+            call = call.bearer_auth(token);
+        }
+        let resp = call.send().await?;
+
+        match resp.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Ok(false.into()),
+            status if !status.is_success() => bail!("Auth webhook answered with {}", status),
+            _ => {}
+        }
+        let answer: WebhookResponse = resp.json().await?;
+        Ok(AuthDecision {
+            allowed: answer.allow,
+            max_sockets: answer
+                .max_sockets
+                .map(|max| u8::try_from(max).unwrap_or(u8::MAX)),
+            ttl: answer.ttl.map(Duration::from_secs),
+        })
+    }
+
+    fn name(&self) -> &str {
+        "WEBHOOK"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use serde_json::{json, Value};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::WebhookAuth;
+    use crate::auth::{Auth, AuthRequest};
+
+    /// Answers every request with `status` and `body`, returning the url and the requests seen
+    /// as their `Authorization` header and JSON body.
+    async fn webhook(
+        status: u16,
+        body: &'static str,
+    ) -> (String, Arc<Mutex<Vec<(Option<String>, Value)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                let (head, length) = loop {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, _)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.strip_prefix("content-length: "))
+                            .map_or(0, |length| length.parse().unwrap());
+                        break (head.to_string(), length);
+                    }
+                };
+                while request.len() < head.len() + 4 + length {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let authorization = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("authorization: "))
+                    .map(str::to_string);
+                let json = serde_json::from_slice(&request[head.len() + 4..]).unwrap();
+                seen.lock().unwrap().push((authorization, json));
+
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    fn request() -> AuthRequest {
+        AuthRequest {
+            subdomain: "demo".to_string(),
+            client_ip: Some("192.0.2.1".parse().unwrap()),
+            credential: Some("secret".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn webhook_receives_the_registration() {
+        let (url, requests) = webhook(200, r#"{"allow":true}"#).await;
+        let auth = WebhookAuth::new(url, Some("server-token".to_string()));
+
+        assert!(auth.authorize(&request()).await.unwrap());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [(
+                Some("Bearer server-token".to_string()),
+                json!({"subdomain": "demo", "credential": "secret", "client_ip": "192.0.2.1"})
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_decides() {
+        let decide = |status, body| async move {
+            let (url, _) = webhook(status, body).await;
+            WebhookAuth::new(url, None).decide(&request()).await
+        };
+
+        let decision = decide(200, r#"{"allow":true,"max_sockets":3,"ttl":60}"#)
+            .await
+            .unwrap();
+        assert!(decision.allowed);
+        assert_eq!(decision.max_sockets, Some(3));
+        assert_eq!(decision.ttl, Some(Duration::from_secs(60)));
+
+        let decision = decide(200, r#"{"allow":true,"max_sockets":300}"#)
+            .await
+            .unwrap();
+        assert_eq!(decision.max_sockets, Some(u8::MAX));
+        assert_eq!(decision.ttl, None);
+
+        assert!(!decide(200, r#"{"allow":false}"#).await.unwrap().allowed);
+        assert!(!decide(403, "").await.unwrap().allowed);
+        assert!(decide(500, r#"{"allow":true}"#).await.is_err());
+        assert!(decide(200, "not json").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_webhook_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        drop(listener);
+
+        assert!(WebhookAuth::new(url, None)
+            .decide(&request())
+            .await
+            .is_err());
+    }
+}